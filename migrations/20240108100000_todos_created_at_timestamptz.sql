@@ -0,0 +1,6 @@
+ALTER TABLE todos
+    ALTER COLUMN created_at TYPE TIMESTAMPTZ USING created_at AT TIME ZONE 'UTC',
+    ALTER COLUMN created_at SET DEFAULT CURRENT_TIMESTAMP;
+
+-- Keyset pagination walks todos in (created_at, id) order.
+CREATE INDEX IF NOT EXISTS todos_created_at_id_idx ON todos (created_at, id);
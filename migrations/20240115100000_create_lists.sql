@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS lists
+(
+    id          BIGSERIAL PRIMARY KEY,
+    name        TEXT NOT NULL,
+    created_at  TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Todos without a list are allowed; deleting a list takes its todos out of
+-- it, after `delete_list` has sent them to the trash.
+ALTER TABLE todos
+    ADD COLUMN IF NOT EXISTS list_id BIGINT REFERENCES lists (id) ON DELETE SET NULL;
+
+CREATE INDEX IF NOT EXISTS todos_list_id_idx ON todos (list_id);
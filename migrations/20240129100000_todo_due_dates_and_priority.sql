@@ -0,0 +1,26 @@
+ALTER TABLE todos
+    ADD COLUMN IF NOT EXISTS due_at TIMESTAMPTZ,
+    -- TEXT rather than an enum type, so plain `SELECT *` queries can still decode it.
+    ADD COLUMN IF NOT EXISTS priority TEXT NOT NULL DEFAULT 'normal'
+        CHECK (priority IN ('low', 'normal', 'high', 'urgent')),
+    -- NULL for todos that were completed before this column existed.
+    ADD COLUMN IF NOT EXISTS completed_at TIMESTAMPTZ;
+
+CREATE INDEX IF NOT EXISTS todos_due_at_idx ON todos (due_at) WHERE NOT done;
+
+-- `completed_at` follows `done` however the row is written.
+CREATE OR REPLACE FUNCTION set_todo_completed_at() RETURNS TRIGGER AS $$
+BEGIN
+    IF NEW.done AND (TG_OP = 'INSERT' OR NOT OLD.done) THEN
+        NEW.completed_at := CURRENT_TIMESTAMP;
+    ELSIF NOT NEW.done THEN
+        NEW.completed_at := NULL;
+    END IF;
+
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER todos_set_completed_at
+    BEFORE INSERT OR UPDATE OF done ON todos
+    FOR EACH ROW EXECUTE FUNCTION set_todo_completed_at();
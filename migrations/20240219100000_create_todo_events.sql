@@ -0,0 +1,15 @@
+-- One row per change to a todo, so edits by several people can be traced.
+-- There is no foreign key on `todo_id`: the history of a todo is kept after
+-- the todo is purged from the trash.
+CREATE TABLE IF NOT EXISTS todo_events (
+    id BIGSERIAL PRIMARY KEY,
+    todo_id BIGINT NOT NULL,
+    kind TEXT NOT NULL
+        CHECK (kind IN ('created', 'updated', 'completed', 'deleted', 'restored')),
+    actor TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    -- `{"field": {"from": ..., "to": ...}}` for every field that changed
+    changes JSONB NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS todo_events_todo_id_idx ON todo_events (todo_id, id);
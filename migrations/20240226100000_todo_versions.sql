@@ -0,0 +1,2 @@
+-- Bumped by every write to a todo, and served as its ETag.
+ALTER TABLE todos ADD COLUMN IF NOT EXISTS version BIGINT NOT NULL DEFAULT 1;
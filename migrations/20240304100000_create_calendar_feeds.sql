@@ -0,0 +1,10 @@
+-- The secret token in the URL of each actor's iCalendar feed. Rotating the
+-- token replaces the row, which invalidates the old URL.
+CREATE TABLE IF NOT EXISTS calendar_feeds (
+    actor TEXT PRIMARY KEY,
+    token TEXT NOT NULL UNIQUE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    -- The `Last-Modified` of the feed, moved on by every change to what the
+    -- actor can see.
+    changed_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
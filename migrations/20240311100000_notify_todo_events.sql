@@ -0,0 +1,28 @@
+-- Gives every new history event its final id once its transaction commits,
+-- and publishes that id on the `todo_events` channel for `GET /events` to fan
+-- out. The id taken on insert is only provisional: a transaction that took
+-- its ids first can commit last, and a subscriber that resumes after the last
+-- id it saw would then never see its events. Under the lock the final ids are
+-- handed out in the order the transactions commit.
+CREATE OR REPLACE FUNCTION notify_todo_event() RETURNS TRIGGER AS $$
+DECLARE
+    final_id BIGINT;
+BEGIN
+    -- 'todoevnt', held until the commit is visible
+    PERFORM pg_advisory_xact_lock(8390035060420931188);
+
+    UPDATE todo_events
+    SET id = nextval(pg_get_serial_sequence('todo_events', 'id'))
+    WHERE id = NEW.id
+    RETURNING id INTO final_id;
+
+    PERFORM pg_notify('todo_events', final_id::TEXT);
+
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE CONSTRAINT TRIGGER todo_events_notify
+    AFTER INSERT ON todo_events
+    DEFERRABLE INITIALLY DEFERRED
+    FOR EACH ROW EXECUTE FUNCTION notify_todo_event();
@@ -0,0 +1,43 @@
+CREATE TABLE IF NOT EXISTS users (
+    id BIGSERIAL PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Requests that do not say who they are from act as the anonymous user, who
+-- also owns everything created before todos had owners.
+INSERT INTO users (id, name) VALUES (1, 'anonymous') ON CONFLICT DO NOTHING;
+SELECT setval('users_id_seq', (SELECT MAX(id) FROM users));
+
+ALTER TABLE todos
+    ADD COLUMN IF NOT EXISTS owner_id BIGINT NOT NULL DEFAULT 1
+        REFERENCES users (id) ON DELETE CASCADE;
+ALTER TABLE lists
+    ADD COLUMN IF NOT EXISTS owner_id BIGINT NOT NULL DEFAULT 1
+        REFERENCES users (id) ON DELETE CASCADE;
+
+CREATE INDEX IF NOT EXISTS todos_owner_id_idx ON todos (owner_id);
+CREATE INDEX IF NOT EXISTS lists_owner_id_idx ON lists (owner_id);
+
+-- Who else can see a todo, and whether they can change it too.
+CREATE TABLE IF NOT EXISTS todo_shares (
+    todo_id BIGINT NOT NULL REFERENCES todos (id) ON DELETE CASCADE,
+    user_id BIGINT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    permission TEXT NOT NULL CHECK (permission IN ('read', 'write')),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (todo_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS todo_shares_user_id_idx ON todo_shares (user_id);
+
+-- Calendar feeds now belong to users rather than to free-form actor names.
+INSERT INTO users (name) SELECT actor FROM calendar_feeds ON CONFLICT DO NOTHING;
+
+ALTER TABLE calendar_feeds
+    ADD COLUMN IF NOT EXISTS user_id BIGINT REFERENCES users (id) ON DELETE CASCADE;
+UPDATE calendar_feeds SET user_id = users.id FROM users WHERE users.name = calendar_feeds.actor;
+ALTER TABLE calendar_feeds
+    DROP CONSTRAINT IF EXISTS calendar_feeds_pkey,
+    DROP COLUMN IF EXISTS actor,
+    ALTER COLUMN user_id SET NOT NULL,
+    ADD PRIMARY KEY (user_id);
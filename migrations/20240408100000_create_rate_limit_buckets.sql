@@ -0,0 +1,50 @@
+-- Token buckets for rate limiting, shared by every instance of the app.
+-- A bucket is full again at `full_at`, so it can be deleted from then on
+-- without giving anyone more requests.
+CREATE TABLE IF NOT EXISTS rate_limit_buckets (
+    key TEXT PRIMARY KEY,
+    tokens DOUBLE PRECISION NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL,
+    full_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS rate_limit_buckets_full_at_idx ON rate_limit_buckets (full_at);
+
+-- Refills the bucket for the time since it was last used and takes a token
+-- from it if there is one, all under the row lock so that concurrent
+-- requests cannot take the same token.
+CREATE OR REPLACE FUNCTION take_rate_limit_token(
+    bucket_key TEXT,
+    capacity DOUBLE PRECISION,
+    per_second DOUBLE PRECISION,
+    OUT allowed BOOLEAN,
+    OUT remaining DOUBLE PRECISION
+) AS $$
+DECLARE
+    now TIMESTAMPTZ := clock_timestamp();
+BEGIN
+    INSERT INTO rate_limit_buckets (key, tokens, updated_at, full_at)
+    VALUES (bucket_key, capacity, now, now)
+    ON CONFLICT (key) DO NOTHING;
+
+    SELECT LEAST(
+        capacity,
+        buckets.tokens + EXTRACT(EPOCH FROM now - buckets.updated_at)::DOUBLE PRECISION * per_second
+    )
+    INTO remaining
+    FROM rate_limit_buckets AS buckets
+    WHERE buckets.key = bucket_key
+    FOR UPDATE;
+
+    allowed := remaining >= 1;
+    IF allowed THEN
+        remaining := remaining - 1;
+    END IF;
+
+    UPDATE rate_limit_buckets
+    SET tokens = remaining,
+        updated_at = now,
+        full_at = now + make_interval(secs => (capacity - remaining) / per_second)
+    WHERE key = bucket_key;
+END;
+$$ LANGUAGE plpgsql;
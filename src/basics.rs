@@ -25,6 +25,8 @@ use axum::{
     Json, Router,
 }; // use is kind of like import, but it has an advantage of de-duplication and aliasing.
 
+use crate::config::Config;
+
 ///
 /// In this "hello world" example, you can see the core elements of an Axum
 /// web application:
@@ -35,7 +37,7 @@ use axum::{
 /// 4. A listener, which is used to listen for incoming connections.
 /// 5. A call to `axum::serve`, which starts the server.
 ///
-pub async fn hello_world() {
+pub async fn hello_world(config: &Config) {
     // build our application with a route
     let app = Router::new().route("/", get(handler));
 
@@ -47,7 +49,7 @@ pub async fn hello_world() {
     // This works in Rust because the first argument to a method is always a reference to the object itself.
 
     // run it
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:3000")
+    let listener = tokio::net::TcpListener::bind(config.server.bind)
         .await
         .unwrap();
     // Unwrap should not be used in production code.
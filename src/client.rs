@@ -23,6 +23,10 @@ use axum::{
     Json, Router,
 };
 
+use crate::config::Config;
+use crate::error::AppError;
+use crate::middleware::correlation_id;
+
 ///
 /// EXERCISE 1
 ///
@@ -39,10 +43,13 @@ use axum::{
 ///
 ///
 ///
-pub async fn cat_fact_server() {
-    let app = Router::<()>::new().route("/", get(cat_fact_handler));
+pub async fn cat_fact_server(config: &Config) {
+    let app = Router::new()
+        .route("/", get(cat_fact_handler))
+        .layer(axum::middleware::from_fn(correlation_id))
+        .with_state(Upstream::new(config, &config.upstream.cat_facts_url));
 
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:3000")
+    let listener = tokio::net::TcpListener::bind(config.server.bind)
         .await
         .unwrap();
 
@@ -50,17 +57,39 @@ pub async fn cat_fact_server() {
 
     axum::serve(listener, app).await.unwrap();
 }
-async fn cat_fact_handler() -> Html<String> {
+async fn cat_fact_handler(State(upstream): State<Upstream>) -> Result<Html<String>, AppError> {
     //Using reqwest::get and .json, get a random cat fact from https://catfact.ninja/fact and return it as an HTML response.
-    let response = reqwest::get("https://catfact.ninja/fact")
-        .await
-        .unwrap()
+    let response = upstream
+        .client
+        .get(&upstream.url)
+        .send()
+        .await?
+        .error_for_status()?
         .json::<CatFact>()
-        .await
-        .unwrap();
+        .await?;
 
-    Html(format!("<h1>{}</h1>", response.fact))
+    Ok(Html(format!("<h1>{}</h1>", response.fact)))
 }
+/// A third-party API, at the URL it is configured with, and a client to
+/// talk to it that gives up after `upstream.timeout`.
+#[derive(Clone)]
+struct Upstream {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl Upstream {
+    fn new(config: &Config, url: &str) -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(config.upstream.timeout.unsigned())
+                .build()
+                .unwrap(),
+            url: url.trim_end_matches('/').to_string(),
+        }
+    }
+}
+
 #[derive(serde::Deserialize)]
 struct CatFact {
     fact: String,
@@ -102,18 +131,19 @@ struct CatFact {
 /// One has been provided for you in the `posts_server` function. You can
 /// set the body of a request using the `.body` method.`
 ///
-pub async fn posts_server() {
+pub async fn posts_server(config: &Config) {
     // this basically outlines how you shouldn't be using
     // reqwest in a real application. You should create a client
     // that is shared across the application and used just for that client.
-    let client = reqwest::Client::new();
+    let upstream = Upstream::new(config, &config.upstream.posts_url);
 
     let app = Router::new()
         .route("/posts", get(posts_handler))
         .route("/comments", get(comments_handler))
-        .with_state(client);
+        .layer(axum::middleware::from_fn(correlation_id))
+        .with_state(upstream);
 
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:3000")
+    let listener = tokio::net::TcpListener::bind(config.server.bind)
         .await
         .unwrap();
 
@@ -122,32 +152,34 @@ pub async fn posts_server() {
     axum::serve(listener, app).await.unwrap();
 }
 
-async fn posts_handler(State(client): State<reqwest::Client>) -> Json<Vec<Post>> {
+async fn posts_handler(State(upstream): State<Upstream>) -> Result<Json<Vec<Post>>, AppError> {
     //Using reqwest::get and .json, get a random cat fact from https://catfact.ninja/fact and return it as an HTML response.
-    let response = client
-        .get("https://jsonplaceholder.typicode.com/posts")
+    let response = upstream
+        .client
+        .get(format!("{}/posts", upstream.url))
         .send()
-        .await
-        .unwrap()
+        .await?
+        .error_for_status()?
         .json::<Vec<Post>>()
-        .await
-        .unwrap();
+        .await?;
 
-    Json(response)
+    Ok(Json(response))
 }
 
-async fn comments_handler(State(client): State<reqwest::Client>) -> Json<Vec<Comment>> {
+async fn comments_handler(
+    State(upstream): State<Upstream>,
+) -> Result<Json<Vec<Comment>>, AppError> {
     //Using reqwest::get and .json, get a random cat fact from https://catfact.ninja/fact and return it as an HTML response.
-    let response: Vec<Comment> = client
-        .get("https://jsonplaceholder.typicode.com/comments")
+    let response: Vec<Comment> = upstream
+        .client
+        .get(format!("{}/comments", upstream.url))
         .send()
-        .await
-        .unwrap()
+        .await?
+        .error_for_status()?
         .json::<Vec<Comment>>()
-        .await
-        .unwrap();
+        .await?;
 
-    Json(response)
+    Ok(Json(response))
 }
 
 #[derive(serde::Deserialize, serde::Serialize)]
@@ -177,18 +209,19 @@ struct Comment {
 ///
 /// Create a web app that talks to a third-party web server of your choosing using Reqwest.
 
-pub async fn graduation_project() {
+pub async fn graduation_project(config: &Config) {
     // this basically outlines how you shouldn't be using
     // reqwest in a real application. You should create a client
     // that is shared across the application and used just for that client.
-    let client = reqwest::Client::new();
+    let upstream = Upstream::new(config, &config.upstream.wines_url);
 
     let app = Router::new()
         .route("/reds", get(reds_handler))
         .route("/whites", get(whites_handler))
-        .with_state(client);
+        .layer(axum::middleware::from_fn(correlation_id))
+        .with_state(upstream);
 
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:3000")
+    let listener = tokio::net::TcpListener::bind(config.server.bind)
         .await
         .unwrap();
 
@@ -197,32 +230,32 @@ pub async fn graduation_project() {
     axum::serve(listener, app).await.unwrap();
 }
 
-async fn reds_handler(State(client): State<reqwest::Client>) -> Json<Vec<Wine>> {
+async fn reds_handler(State(upstream): State<Upstream>) -> Result<Json<Vec<Wine>>, AppError> {
     //Using reqwest::get and .json, get a random cat fact from https://catfact.ninja/fact and return it as an HTML response.
-    let response = client
-        .get("https://api.sampleapis.com/wines/reds")
+    let response = upstream
+        .client
+        .get(format!("{}/reds", upstream.url))
         .send()
-        .await
-        .unwrap()
+        .await?
+        .error_for_status()?
         .json::<Vec<Wine>>()
-        .await
-        .unwrap();
+        .await?;
 
-    Json(response)
+    Ok(Json(response))
 }
 
-async fn whites_handler(State(client): State<reqwest::Client>) -> Json<Vec<Wine>> {
+async fn whites_handler(State(upstream): State<Upstream>) -> Result<Json<Vec<Wine>>, AppError> {
     //Using reqwest::get and .json, get a random cat fact from https://catfact.ninja/fact and return it as an HTML response.
-    let response = client
-        .get("https://api.sampleapis.com/wines/whites")
+    let response = upstream
+        .client
+        .get(format!("{}/whites", upstream.url))
         .send()
-        .await
-        .unwrap()
+        .await?
+        .error_for_status()?
         .json::<Vec<Wine>>()
-        .await
-        .unwrap();
+        .await?;
 
-    Json(response)
+    Ok(Json(response))
 }
 
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
@@ -23,13 +23,20 @@ use std::{collections::HashMap, sync::Arc};
 
 #[allow(unused_imports)]
 use axum::extract::State;
+use axum::middleware::from_fn_with_state;
 #[allow(unused_imports)]
 use axum::{body::Body, http::Method, routing::*};
-use axum::{extract::Path, response::IntoResponse, Json};
+use axum::{extract::Path, Json};
 #[allow(unused_imports)]
 use hyper::Request;
-use hyper::{Response, StatusCode};
+use hyper::StatusCode;
 use tokio::sync::Mutex;
+use validator::Validate;
+
+use crate::config::Config;
+use crate::error::AppError;
+use crate::middleware::{correlation_id, idempotency, IdempotencyStore};
+use crate::validation::{not_blank, ValidatedJson};
 
 ///
 /// EXERCISE 1
@@ -469,22 +476,28 @@ async fn extension_gbp_to_usd_handler() -> String {
 ///
 /// GET /users
 /// GET /users/:id
-/// POST /users
+/// POST /users            (Idempotency-Key)
 /// PUT /users/:id
 /// DELETE /users/:id
 ///
 /// Place it into a web server and test to ensure it meets your requirements.
 ///
-pub async fn run_users_server() {
+pub async fn run_users_server(config: &Config) {
+    let idempotency_store = IdempotencyStore::new(config.server.idempotency_ttl.unsigned());
+
     let app = Router::new()
         .route("/users", get(get_users))
         .route("/users/:id", get(get_user))
-        .route("/users", post(create_user))
+        .route(
+            "/users",
+            post(create_user).layer(from_fn_with_state(idempotency_store, idempotency)),
+        )
         .route("/users/:id", put(update_user))
         .route("/users/:id", delete(delete_user))
+        .layer(axum::middleware::from_fn(correlation_id))
         .with_state(UsersState::new());
 
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:3000")
+    let listener = tokio::net::TcpListener::bind(config.server.bind)
         .await
         .unwrap();
 
@@ -499,36 +512,29 @@ async fn get_users(State(state): State<UsersState>) -> Json<Vec<User>> {
 async fn get_user(
     State(state): State<UsersState>,
     Path(id): Path<u64>,
-) -> Result<Json<User>, MissingUser> {
+) -> Result<Json<User>, AppError> {
     match state.get_user(id).await {
         Some(user) => Ok(Json(user)),
-        None => Err(MissingUser { id }),
+        None => Err(missing_user(id)),
     }
 }
 async fn create_user(
     State(state): State<UsersState>,
-    Json(create_request): Json<UserWithoutId>,
-) -> Json<CreateUserResponse> {
+    ValidatedJson(create_request): ValidatedJson<UserWithoutId>,
+) -> Result<Json<CreateUserResponse>, AppError> {
     let id = state.create_user(create_request).await;
 
-    Json(CreateUserResponse { id })
+    Ok(Json(CreateUserResponse { id }))
 }
 async fn update_user(
     State(state): State<UsersState>,
     Path(id): Path<u64>,
-    Json(update_request): Json<UpdateUserRequest>,
-) -> Result<(), MissingUser> {
-    let result = state.update_user(id, update_request).await;
-
-    result.map_err(|missing_user| missing_user)
+    ValidatedJson(update_request): ValidatedJson<UpdateUserRequest>,
+) -> Result<(), AppError> {
+    state.update_user(id, update_request).await
 }
-async fn delete_user(
-    State(state): State<UsersState>,
-    Path(id): Path<u64>,
-) -> Result<(), MissingUser> {
-    let result = state.delete_user(id).await;
-
-    result.map_err(|missing_user| missing_user)
+async fn delete_user(State(state): State<UsersState>, Path(id): Path<u64>) -> Result<(), AppError> {
+    state.delete_user(id).await
 }
 
 #[derive(Clone)]
@@ -580,7 +586,7 @@ impl UsersState {
         id
     }
 
-    async fn update_user(&self, id: u64, update: UpdateUserRequest) -> Result<(), MissingUser> {
+    async fn update_user(&self, id: u64, update: UpdateUserRequest) -> Result<(), AppError> {
         let mut guard = self.users.lock().await;
 
         if let Some(user) = guard.get_mut(&id) {
@@ -594,39 +600,27 @@ impl UsersState {
 
             Ok(())
         } else {
-            Err(MissingUser { id })
+            Err(missing_user(id))
         }
     }
 
-    async fn delete_user(&self, id: u64) -> Result<(), MissingUser> {
+    async fn delete_user(&self, id: u64) -> Result<(), AppError> {
         let mut guard = self.users.lock().await;
 
         if guard.remove(&id).is_some() {
             Ok(())
         } else {
-            Err(MissingUser { id })
+            Err(missing_user(id))
         }
     }
 }
 
-impl IntoResponse for MissingUser {
-    fn into_response(self) -> axum::http::Response<Body> {
-        let response = MissingUserErrorDetails {
-            id: self.id,
-            message: format!("User with id {} not found", self.id),
-        };
-
-        Response::builder()
-            .status(StatusCode::NOT_FOUND)
-            .body(Body::from(serde_json::to_string(&response).unwrap()))
-            .unwrap()
-    }
-}
-
-#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq, Eq)]
-struct MissingUserErrorDetails {
-    id: u64,
-    message: String,
+fn missing_user(id: u64) -> AppError {
+    AppError::problem(
+        StatusCode::NOT_FOUND,
+        "user-not-found",
+        format!("User with id {} not found", id),
+    )
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq, Eq)]
@@ -636,15 +630,19 @@ struct User {
     email: String,
 }
 
-#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq, Eq)]
+#[derive(serde::Deserialize, serde::Serialize, Validate, Clone, Debug, PartialEq, Eq)]
 struct UpdateUserRequest {
+    #[validate(length(min = 1, max = 100), custom(function = "not_blank"))]
     name: Option<String>,
+    #[validate(email)]
     email: Option<String>,
 }
 
-#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq, Eq)]
+#[derive(serde::Deserialize, serde::Serialize, Validate, Clone, Debug, PartialEq, Eq)]
 struct UserWithoutId {
+    #[validate(length(min = 1, max = 100), custom(function = "not_blank"))]
     name: String,
+    #[validate(email)]
     email: String,
 }
 
@@ -652,8 +650,3 @@ struct UserWithoutId {
 struct CreateUserResponse {
     id: u64,
 }
-
-#[derive(serde::Serialize, Clone, Debug, PartialEq, Eq)]
-struct MissingUser {
-    id: u64,
-}
@@ -0,0 +1,491 @@
+//!
+//! ERRORS
+//! ------
+//!
+//! Every module answers errors the same way: as an RFC 7807 problem details
+//! object, served as `application/problem+json`. Handlers return `AppError`,
+//! or an error of their own that converts into it, and the problem is built
+//! from that.
+//!
+//! Bodies that break the rules of their type become a 422 that lists every
+//! broken rule under `errors`; see `ValidatedJson`.
+//!
+//! Each problem carries the correlation id of its request, which the
+//! `correlation_id` middleware takes from the `X-Correlation-Id` header or
+//! makes up, so that a failing request can be found in the logs. `log`
+//! writes it next to anything logged while answering the request.
+//!
+
+use axum::{
+    extract::rejection::JsonRejection,
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::error::Category;
+use std::sync::OnceLock;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::config::LogFormat;
+
+pub(crate) const PROBLEM_JSON: &str = "application/problem+json";
+pub(crate) const CORRELATION_ID_HEADER: &str = "x-correlation-id";
+
+static LOG_FORMAT: OnceLock<LogFormat> = OnceLock::new();
+
+tokio::task_local! {
+    static REQUEST: RequestContext;
+}
+
+/// What errors need to know about the request they answer.
+#[derive(Clone, Debug)]
+struct RequestContext {
+    correlation_id: String,
+    path: String,
+}
+
+/// Runs the future, which answers the request for the given path, with the
+/// correlation id available to every `AppError` it renders.
+pub(crate) async fn in_request<F: std::future::Future>(
+    correlation_id: String,
+    path: String,
+    future: F,
+) -> F::Output {
+    REQUEST
+        .scope(
+            RequestContext {
+                correlation_id,
+                path,
+            },
+            future,
+        )
+        .await
+}
+
+/// Sets how `log` writes lines, once, at startup. Lines are text until then.
+pub(crate) fn set_log_format(format: LogFormat) {
+    let _ = LOG_FORMAT.set(format);
+}
+
+/// Logs the message, with the correlation id of the request being answered.
+pub(crate) fn log(message: impl std::fmt::Display) {
+    let correlation_id = REQUEST
+        .try_with(|request| request.correlation_id.clone())
+        .ok();
+
+    log_with(correlation_id.as_deref(), message);
+}
+
+fn log_with(correlation_id: Option<&str>, message: impl std::fmt::Display) {
+    match (
+        LOG_FORMAT.get().copied().unwrap_or_default(),
+        correlation_id,
+    ) {
+        (LogFormat::Text, Some(correlation_id)) => println!("[{}] {}", correlation_id, message),
+        (LogFormat::Text, None) => println!("{}", message),
+        (LogFormat::Json, correlation_id) => println!(
+            "{}",
+            serde_json::json!({
+                "timestamp": OffsetDateTime::now_utc().format(&Rfc3339).unwrap_or_default(),
+                "correlation_id": correlation_id,
+                "message": message.to_string(),
+            })
+        ),
+    }
+}
+
+/// 16 random bytes in hex.
+pub(crate) fn new_correlation_id() -> String {
+    rand::random::<[u8; 16]>()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+///
+/// An error any handler can return. Problems with the request itself carry
+/// their status, the kind of problem (which becomes its `type`) and a detail
+/// for the client. The other variants are failures the client can do
+/// nothing about, so their details are only logged.
+///
+#[derive(Debug)]
+pub(crate) enum AppError {
+    Problem {
+        status: StatusCode,
+        kind: &'static str,
+        detail: String,
+    },
+    /// The body of the request is not the JSON the handler expects.
+    Json(JsonRejection),
+    /// The body of the request could not be read as JSON of the expected
+    /// shape. `path` leads to the value that could not be deserialized.
+    InvalidJson {
+        path: String,
+        error: serde_json::Error,
+    },
+    /// The body of the request is well formed, but breaks these rules.
+    Invalid(Vec<Violation>),
+    Database(sqlx::Error),
+    /// A service the app relies on failed or could not be reached.
+    Upstream(reqwest::Error),
+    Internal(String),
+}
+
+impl AppError {
+    pub(crate) fn problem(
+        status: StatusCode,
+        kind: &'static str,
+        detail: impl Into<String>,
+    ) -> Self {
+        AppError::Problem {
+            status,
+            kind,
+            detail: detail.into(),
+        }
+    }
+
+    fn status_kind_and_detail(self, correlation_id: &str) -> (StatusCode, &'static str, String) {
+        match self {
+            AppError::Problem {
+                status,
+                kind,
+                detail,
+            } => (status, kind, detail),
+            // The same status for each kind as `InvalidJson`, rather than
+            // axum's 422 for data errors, which is kept for broken rules.
+            AppError::Json(rejection) => {
+                let (status, kind) = match rejection {
+                    JsonRejection::JsonSyntaxError(_) => (StatusCode::BAD_REQUEST, "invalid-json"),
+                    JsonRejection::JsonDataError(_) => {
+                        (StatusCode::BAD_REQUEST, "invalid-json-data")
+                    }
+                    JsonRejection::MissingJsonContentType(_) => (
+                        StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                        "missing-json-content-type",
+                    ),
+                    _ => (rejection.status(), "unreadable-body"),
+                };
+
+                (status, kind, rejection.body_text())
+            }
+            AppError::InvalidJson { error, .. } => match error.classify() {
+                Category::Syntax | Category::Eof => (
+                    StatusCode::BAD_REQUEST,
+                    "invalid-json",
+                    format!("Failed to parse the request body as JSON: {}", error),
+                ),
+                Category::Data => (
+                    StatusCode::BAD_REQUEST,
+                    "invalid-json-data",
+                    format!(
+                        "Failed to deserialize the JSON body into the target type: {}",
+                        error
+                    ),
+                ),
+                Category::Io => (
+                    StatusCode::BAD_REQUEST,
+                    "unreadable-body",
+                    format!("Failed to read the request body: {}", error),
+                ),
+            },
+            AppError::Invalid(violations) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "validation-failed",
+                match violations.len() {
+                    1 => "The request body breaks 1 rule".to_string(),
+                    count => format!("The request body breaks {} rules", count),
+                },
+            ),
+            AppError::Database(
+                sqlx::Error::PoolTimedOut | sqlx::Error::PoolClosed | sqlx::Error::Io(_),
+            ) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "database-unavailable",
+                "The database is currently unavailable".to_string(),
+            ),
+            AppError::Database(error) => {
+                log_with(
+                    Some(correlation_id),
+                    format_args!("Database error: {}", error),
+                );
+
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "database-error",
+                    "Internal database error".to_string(),
+                )
+            }
+            AppError::Upstream(error) => {
+                log_with(
+                    Some(correlation_id),
+                    format_args!("Upstream error: {}", error),
+                );
+
+                if error.is_timeout() {
+                    (
+                        StatusCode::GATEWAY_TIMEOUT,
+                        "upstream-timeout",
+                        "A service this app relies on took too long to answer".to_string(),
+                    )
+                } else {
+                    (
+                        StatusCode::BAD_GATEWAY,
+                        "upstream-error",
+                        "A service this app relies on failed".to_string(),
+                    )
+                }
+            }
+            AppError::Internal(message) => {
+                log_with(
+                    Some(correlation_id),
+                    format_args!("Internal error: {}", message),
+                );
+
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "internal-error",
+                    "Internal error".to_string(),
+                )
+            }
+        }
+    }
+}
+
+// The field that could not be deserialized, and why. A missing field is
+// reported against the field itself rather than the object it is missing
+// from.
+fn json_violation(path: &str, error: &serde_json::Error) -> Violation {
+    let path = if path == "." { "" } else { path };
+    let message = error.to_string();
+    let message = message
+        .strip_suffix(&format!(
+            " at line {} column {}",
+            error.line(),
+            error.column()
+        ))
+        .unwrap_or(&message);
+
+    match message
+        .strip_prefix("missing field `")
+        .and_then(|field| field.strip_suffix('`'))
+    {
+        Some(field) => Violation {
+            field: if path.is_empty() {
+                field.to_string()
+            } else {
+                format!("{}.{}", path, field)
+            },
+            rule: "required".to_string(),
+            message: "is required".to_string(),
+        },
+        None => Violation {
+            field: path.to_string(),
+            rule: "type".to_string(),
+            message: message.to_string(),
+        },
+    }
+}
+
+impl From<sqlx::Error> for AppError {
+    fn from(error: sqlx::Error) -> Self {
+        AppError::Database(error)
+    }
+}
+
+impl From<reqwest::Error> for AppError {
+    fn from(error: reqwest::Error) -> Self {
+        AppError::Upstream(error)
+    }
+}
+
+impl From<JsonRejection> for AppError {
+    fn from(rejection: JsonRejection) -> Self {
+        AppError::Json(rejection)
+    }
+}
+
+///
+/// The body of every error response. Problems are told apart by `type`,
+/// which is a URI reference named after the kind of problem; `title` is the
+/// same for every problem of a status.
+///
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Problem {
+    #[serde(rename = "type")]
+    pub(crate) problem_type: String,
+    pub(crate) title: String,
+    pub(crate) status: u16,
+    pub(crate) detail: String,
+    /// The path of the request that failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) instance: Option<String>,
+    pub(crate) correlation_id: String,
+    /// Every rule the body of the request broke.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) errors: Vec<Violation>,
+    /// Where in the body of the request it stopped being the JSON expected.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) line: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) column: Option<usize>,
+}
+
+/// A rule of its type that a field in the body of a request broke.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Violation {
+    /// The path of the field, like `title` or `addresses[1].postcode`.
+    pub(crate) field: String,
+    pub(crate) rule: String,
+    pub(crate) message: String,
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        // errors rendered outside of `correlation_id` still get an id of
+        // their own, though no path
+        let (correlation_id, instance) = REQUEST
+            .try_with(|request| (request.correlation_id.clone(), Some(request.path.clone())))
+            .unwrap_or_else(|_| (new_correlation_id(), None));
+
+        let (errors, location) = match &self {
+            AppError::Invalid(violations) => (violations.clone(), None),
+            AppError::InvalidJson { error, .. } if error.is_io() => (Vec::new(), None),
+            AppError::InvalidJson { path, error } => (
+                if error.is_data() {
+                    vec![json_violation(path, error)]
+                } else {
+                    Vec::new()
+                },
+                Some((error.line(), error.column())),
+            ),
+            _ => (Vec::new(), None),
+        };
+
+        let (status, kind, detail) = self.status_kind_and_detail(&correlation_id);
+        let problem = Problem {
+            problem_type: format!("/problems/{}", kind),
+            title: status.canonical_reason().unwrap_or("Error").to_string(),
+            status: status.as_u16(),
+            detail,
+            instance,
+            correlation_id: correlation_id.clone(),
+            errors,
+            line: location.map(|(line, _)| line),
+            column: location.map(|(_, column)| column),
+        };
+
+        let mut response = (
+            status,
+            [(header::CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON))],
+            Json(problem),
+        )
+            .into_response();
+        if let Ok(correlation_id) = HeaderValue::from_str(&correlation_id) {
+            response
+                .headers_mut()
+                .insert(CORRELATION_ID_HEADER, correlation_id);
+        }
+
+        response
+    }
+}
+
+#[tokio::test]
+async fn app_errors_render_problem_details() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+
+    let response = in_request("abc123".to_string(), "/todos/7".to_string(), async {
+        AppError::problem(StatusCode::NOT_FOUND, "todo-not-found", "Todo 7 not found")
+            .into_response()
+    })
+    .await;
+
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], PROBLEM_JSON);
+    assert_eq!(response.headers()[CORRELATION_ID_HEADER], "abc123");
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let problem: Problem = serde_json::from_slice(&body).unwrap();
+    assert_eq!(
+        problem,
+        Problem {
+            problem_type: "/problems/todo-not-found".to_string(),
+            title: "Not Found".to_string(),
+            status: 404,
+            detail: "Todo 7 not found".to_string(),
+            instance: Some("/todos/7".to_string()),
+            correlation_id: "abc123".to_string(),
+            errors: Vec::new(),
+            line: None,
+            column: None,
+        }
+    );
+
+    // failures the client cannot fix keep their details to the logs
+    let response = AppError::from(sqlx::Error::Protocol("secret".to_string())).into_response();
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let problem: Problem = serde_json::from_slice(&body).unwrap();
+    assert_eq!(problem.problem_type, "/problems/database-error");
+    assert!(!problem.detail.contains("secret"));
+    assert_eq!(problem.correlation_id.len(), 32);
+
+    let response = AppError::from(sqlx::Error::PoolTimedOut).into_response();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[tokio::test]
+async fn json_rejections_become_problems() {
+    use axum::{body::Body, http::Method, routing::post, Router};
+    use hyper::Request;
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+
+    #[derive(serde::Deserialize)]
+    struct Greeting {
+        #[allow(dead_code)]
+        name: String,
+    }
+
+    let app = Router::new().route(
+        "/",
+        post(
+            |greeting: Result<Json<Greeting>, JsonRejection>| async move {
+                greeting
+                    .map(|_| StatusCode::NO_CONTENT)
+                    .map_err(AppError::from)
+            },
+        ),
+    );
+
+    let cases = [
+        ("{", StatusCode::BAD_REQUEST, "/problems/invalid-json"),
+        (
+            r#"{"name": 7}"#,
+            StatusCode::BAD_REQUEST,
+            "/problems/invalid-json-data",
+        ),
+    ];
+    for (body, status, problem_type) in cases {
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/")
+                    .header("Content-Type", "application/json")
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), status);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let problem: Problem = serde_json::from_slice(&body).unwrap();
+        assert_eq!(problem.problem_type, problem_type);
+        assert_eq!(problem.status, status.as_u16());
+    }
+}
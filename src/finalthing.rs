@@ -1,22 +1,3394 @@
+//!
+//! FINAL THING
+//! -----------
+//!
+//! The persistence layer for the todo app, following exercises 3-5 of the
+//! architecture section: handlers only ever see the `TodoRepo` trait, which
+//! has a "live" implementation backed by Postgres and an in-memory one that
+//! lets the handlers be tested without a database.
+//!
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use async_trait::async_trait;
+use base64::Engine as _;
+use futures::{stream::BoxStream, StreamExt};
+use sqlx::postgres::PgListener;
+use sqlx::{Connection, Executor, FromRow, PgConnection, PgExecutor, Pool, Postgres, QueryBuilder};
+use time::OffsetDateTime;
+use tokio::sync::{broadcast, Mutex};
+
+use crate::error::log;
+use crate::middleware::{ApiKey, Scope};
+use crate::persistence::{
+    check_rules, normalize_tags, Account, Actor, BatchMode, BatchOperation, BatchOutcome,
+    CreateTodo, EventKind, PageRequest, PatchTodo, Permission, Priority, SortField, TagCount,
+    TagMatch, Todo, TodoChange, TodoCursor, TodoError, TodoEvent, TodoList, TodoPage, TodoQuery,
+    TodoShare, TodoSort, TodoTree, ANONYMOUS_USER_ID, DEFAULT_MAX_DEPTH,
+};
+
+// Every column of `Todo`, for queries built at runtime (defined up here so
+// that `concat!` can use it anywhere in the file). `fetch_todo` is the
+// `query_as!` counterpart and must be kept in step with it.
+macro_rules! todo_select {
+    () => {
+        "SELECT id, title, description, done, created_at, list_id,
+            ARRAY(SELECT tags.name FROM todo_tags JOIN tags ON tags.id = todo_tags.tag_id
+                  WHERE todo_tags.todo_id = todos.id ORDER BY tags.name) AS tags,
+            due_at, priority, completed_at, parent_id, deleted_at, version, owner_id
+        FROM todos"
+    };
+}
+
+///
+/// Every method that changes a todo records what changed, and who by, in the
+/// history of the todo.
+///
+/// The actor only ever sees the todos they own or that are shared with them,
+/// and their own lists. A todo they cannot see is treated as if it did not
+/// exist; one they can see but not change is `Forbidden`.
+///
+/// Todos that break the rules of `CreateTodo` or `PatchTodo` are rejected
+/// with `Invalid`, so that batches and imports check the same rules as
+/// `ValidatedJson` does for single requests.
+///
 #[async_trait]
-trait TodoRepo {
-    async fn get_all(&self) -> Vec<Todo>;
+pub(crate) trait TodoRepo: Send + Sync {
+    /// The user with the given name, created on first use.
+    async fn user(&self, name: &str) -> Result<Actor, TodoError>;
+
+    /// Creates a user with an account with the given password hash. Fails
+    /// with `NameTaken` if there already is a user with the name, whether or
+    /// not they have an account, as their todos would go along with it.
+    async fn register(
+        &self,
+        name: &str,
+        email: &str,
+        password_hash: &str,
+    ) -> Result<Account, TodoError>;
+
+    /// The account with the given name along with its password hash, or
+    /// `None` if the user does not exist or has no account.
+    async fn credentials(&self, name: &str) -> Result<Option<(Account, String)>, TodoError>;
+
+    /// Starts a session for the account, kept under the hash of its token.
+    async fn create_session(
+        &self,
+        user_id: i64,
+        token_hash: &str,
+        expires_at: OffsetDateTime,
+    ) -> Result<(), TodoError>;
+
+    /// The account the session is for, if it has not expired.
+    async fn session_account(&self, token_hash: &str) -> Result<Option<Account>, TodoError>;
+
+    /// Ends the session, if there is one.
+    async fn delete_session(&self, token_hash: &str) -> Result<(), TodoError>;
+
+    /// Stores an API key for requests made as the user, under the hash of
+    /// the key.
+    async fn create_api_key(
+        &self,
+        user: &Actor,
+        name: &str,
+        key_hash: &str,
+        scopes: &[Scope],
+        expires_at: Option<OffsetDateTime>,
+    ) -> Result<ApiKey, TodoError>;
+
+    /// Every API key, oldest first, including those that have expired.
+    async fn api_keys(&self) -> Result<Vec<ApiKey>, TodoError>;
+
+    /// The API key stored under the hash, whether it has expired or not.
+    async fn api_key(&self, key_hash: &str) -> Result<Option<ApiKey>, TodoError>;
+
+    /// Records when the API key was last used.
+    async fn touch_api_key(&self, id: i64, used_at: OffsetDateTime) -> Result<(), TodoError>;
+
+    /// Deletes the API key. Returns `false` if there was none with the id.
+    async fn revoke_api_key(&self, id: i64) -> Result<bool, TodoError>;
+
+    /// Lists the todos matching the query in its sort order, starting after
+    /// the cursor.
+    async fn list(
+        &self,
+        actor: &Actor,
+        query: &TodoQuery,
+        page: PageRequest,
+    ) -> Result<TodoPage, TodoError>;
+
+    /// Creates a todo owned by the actor. Subtasks always have the same owner
+    /// as their parent, so only the owner of a todo can add them.
+    async fn create(&self, actor: &Actor, create: CreateTodo) -> Result<Todo, TodoError>;
+
+    async fn get(&self, actor: &Actor, id: i64) -> Result<Option<Todo>, TodoError>;
+
+    /// The todo with all of its subtasks nested below it.
+    async fn subtree(&self, actor: &Actor, id: i64) -> Result<Option<TodoTree>, TodoError>;
+
+    /// Updates only the fields that are `Some`, returning `None` if there is
+    /// no todo with the given id. Rejects a new parent that would form a
+    /// cycle or nest the todo too deeply, and an `expected_version` that is
+    /// no longer current. Only the owner can give the todo a new parent.
+    async fn update(
+        &self,
+        actor: &Actor,
+        id: i64,
+        patch: PatchTodo,
+        expected_version: Option<i64>,
+    ) -> Result<Option<Todo>, TodoError>;
+
+    /// Moves the todo together with its subtasks into the trash, hiding them
+    /// from everything but `trash`. Returns `false` if there was no todo with
+    /// the given id.
+    async fn delete(
+        &self,
+        actor: &Actor,
+        id: i64,
+        expected_version: Option<i64>,
+    ) -> Result<bool, TodoError>;
+
+    /// Takes the todo out of the trash, along with the subtasks that were
+    /// deleted with it. Returns `None` if the todo is not in the trash.
+    async fn restore(&self, actor: &Actor, id: i64) -> Result<Option<Todo>, TodoError>;
+
+    /// The deleted todos of the actor, most recently deleted first.
+    async fn trash(&self, actor: &Actor) -> Result<Vec<Todo>, TodoError>;
+
+    /// Permanently deletes the todos that went into the trash before the
+    /// given time, returning how many there were. Their history is kept.
+    async fn purge(&self, deleted_before: OffsetDateTime) -> Result<u64, TodoError>;
+
+    /// Every change made to the todo, oldest first. Returns `None` if there
+    /// is no todo with the given id, in the trash or out of it.
+    async fn history(&self, actor: &Actor, id: i64) -> Result<Option<Vec<TodoEvent>>, TodoError>;
+
+    /// Up to `limit` changes to todos the actor can see, recorded after the
+    /// event with the given id, oldest first.
+    async fn changes_since(
+        &self,
+        actor: &Actor,
+        after: i64,
+        limit: i64,
+    ) -> Result<Vec<TodoChange>, TodoError>;
+
+    /// Every change to a todo from now on, as soon as it is committed, for
+    /// every user. Subscribers pick out the changes for their readers.
+    fn subscribe(&self) -> broadcast::Receiver<TodoChange>;
+
+    /// Streams every todo of the actor outside the trash, oldest first,
+    /// without holding them all in memory.
+    async fn export(
+        &self,
+        actor: &Actor,
+    ) -> Result<BoxStream<'static, Result<Todo, TodoError>>, TodoError>;
+
+    /// Runs the operations in order inside a single transaction, returning
+    /// an outcome for each. In atomic mode the first failure rolls back the
+    /// whole batch; otherwise only the failed operation is undone.
+    async fn batch(
+        &self,
+        actor: &Actor,
+        mode: BatchMode,
+        operations: Vec<BatchOperation>,
+    ) -> Result<Vec<BatchOutcome>, TodoError>;
+
+    /// Moves the todo into another list of the owner, or out of any list for
+    /// `None`.
+    async fn move_todo(
+        &self,
+        actor: &Actor,
+        id: i64,
+        list_id: Option<i64>,
+    ) -> Result<Option<Todo>, TodoError>;
+
+    /// Adds the tags to the todo, creating any that do not exist yet.
+    async fn add_tags(
+        &self,
+        actor: &Actor,
+        id: i64,
+        tags: Vec<String>,
+    ) -> Result<Option<Todo>, TodoError>;
 
-    async fn create(&self, title: String, description: String) -> Todo;
+    async fn remove_tag(
+        &self,
+        actor: &Actor,
+        id: i64,
+        tag: String,
+    ) -> Result<Option<Todo>, TodoError>;
 
-    async fn get(&self, id: i32) -> Option<Todo>;
+    /// Every tag on a todo the actor can see, along with the number of those
+    /// todos outside the trash carrying it.
+    async fn get_tags(&self, actor: &Actor) -> Result<Vec<TagCount>, TodoError>;
 
-    async fn update(&self, id: i32, title: Option<String>, description: Option<String>, done: Option<bool>) -> ();
+    async fn get_lists(&self, actor: &Actor) -> Result<Vec<TodoList>, TodoError>;
 
-    async fn delete(&self, id: i32) -> ();
+    async fn create_list(&self, actor: &Actor, name: String) -> Result<TodoList, TodoError>;
+
+    async fn get_list(&self, actor: &Actor, id: i64) -> Result<Option<TodoList>, TodoError>;
+
+    async fn rename_list(
+        &self,
+        actor: &Actor,
+        id: i64,
+        name: String,
+    ) -> Result<Option<TodoList>, TodoError>;
+
+    /// Deletes the list together with all of its todos. Returns `false` if
+    /// there was no list with the given id.
+    async fn delete_list(&self, actor: &Actor, id: i64) -> Result<bool, TodoError>;
+
+    /// The todos that are not done yet and due in `[from, to)`, soonest
+    /// first. Without `from` this is everything due before `to`.
+    async fn due_between(
+        &self,
+        actor: &Actor,
+        from: Option<OffsetDateTime>,
+        to: OffsetDateTime,
+    ) -> Result<Vec<Todo>, TodoError>;
+
+    /// Every todo outside the trash that has a due date, done or not,
+    /// soonest first.
+    async fn scheduled(&self, actor: &Actor) -> Result<Vec<Todo>, TodoError>;
+
+    /// When the calendar feed of the actor last changed, which is moved on
+    /// by every change to a todo they can see, and by sharing, unsharing or
+    /// purging one. Each change moves it on by at least a second, as HTTP
+    /// dates go no further. Returns `None` if the actor has no feed.
+    async fn last_changed(&self, actor: &Actor) -> Result<Option<OffsetDateTime>, TodoError>;
+
+    /// The token for the calendar feed of the actor, created on first use.
+    async fn calendar_token(&self, actor: &Actor) -> Result<String, TodoError>;
+
+    /// Replaces the token for the calendar feed of the actor, so the old feed
+    /// URL stops working.
+    async fn rotate_calendar_token(&self, actor: &Actor) -> Result<String, TodoError>;
+
+    /// Whose calendar feed the token is for.
+    async fn calendar_owner(&self, token: &str) -> Result<Option<Actor>, TodoError>;
+
+    /// Who the owner of the todo has shared it with, by name.
+    async fn shares(&self, actor: &Actor, id: i64) -> Result<Vec<TodoShare>, TodoError>;
+
+    /// Shares the todo with the named user, or changes what they can do with
+    /// it if it already is.
+    async fn share(
+        &self,
+        actor: &Actor,
+        id: i64,
+        user: &str,
+        permission: Permission,
+    ) -> Result<TodoShare, TodoError>;
+
+    /// Stops sharing the todo with the named user, if it was.
+    async fn unshare(&self, actor: &Actor, id: i64, user: &str) -> Result<(), TodoError>;
 }
 
 #[derive(Debug, Clone)]
-struct TodoRepoPostgres {
-    pool: Pool<Postgres>
+pub(crate) struct TodoRepoPostgres {
+    pool: Pool<Postgres>,
+    max_depth: usize,
+    changes: broadcast::Sender<TodoChange>,
+    /// Set once `listen_for_changes` has been started by the first subscriber.
+    listening: Arc<AtomicBool>,
+}
+
+impl TodoRepoPostgres {
+    pub(crate) fn new(pool: Pool<Postgres>) -> Self {
+        Self {
+            pool,
+            max_depth: DEFAULT_MAX_DEPTH,
+            changes: broadcast::channel(CHANGES_CAPACITY).0,
+            listening: Default::default(),
+        }
+    }
+
+    pub(crate) fn with_max_depth(self, max_depth: usize) -> Self {
+        Self { max_depth, ..self }
+    }
+
+    async fn create_in(
+        &self,
+        conn: &mut PgConnection,
+        actor: &Actor,
+        create: CreateTodo,
+    ) -> Result<Todo, TodoError> {
+        check_rules(&create)?;
+        let tags = normalize_tags(create.tags)?;
+
+        if let Some(list_id) = create.list_id {
+            require_list(&mut *conn, actor, list_id).await?;
+        }
+
+        if let Some(parent_id) = create.parent_id {
+            require_parent(access(&mut *conn, actor, parent_id).await?, parent_id)?;
+            lock_hierarchy(conn).await?;
+            check_parent(conn, None, parent_id, self.max_depth).await?;
+        }
+
+        let id = sqlx::query!(
+            "INSERT INTO todos
+                (title, description, done, list_id, due_at, priority, parent_id, owner_id)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+             RETURNING id",
+            create.title,
+            create.description,
+            create.done,
+            create.list_id,
+            create.due_at,
+            create.priority as Priority,
+            create.parent_id,
+            actor.id
+        )
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(|error| list_not_found(error, create.list_id))?
+        .id;
+
+        insert_tags(conn, id, &tags).await?;
+
+        let todo = fetch_todo(&mut *conn, id)
+            .await?
+            .ok_or(TodoError::NotFound { id })?;
+
+        record_events(conn, actor, &[], std::slice::from_ref(&todo)).await?;
+
+        Ok(todo)
+    }
+
+    async fn update_in(
+        &self,
+        conn: &mut PgConnection,
+        actor: &Actor,
+        id: i64,
+        patch: PatchTodo,
+        expected_version: Option<i64>,
+    ) -> Result<Option<Todo>, TodoError> {
+        check_rules(&patch)?;
+
+        let needed = match patch.parent_id {
+            Some(_) => Access::Owner,
+            None => Access::Write,
+        };
+        if !permits(access(&mut *conn, actor, id).await?, needed, id)? {
+            return Ok(None);
+        }
+
+        if let Some(Some(parent_id)) = patch.parent_id {
+            require_parent(access(&mut *conn, actor, parent_id).await?, parent_id)?;
+            lock_hierarchy(conn).await?;
+            check_parent(conn, Some(id), parent_id, self.max_depth).await?;
+        }
+
+        let cascade = patch.cascade && patch.done == Some(true);
+        let ids = if cascade {
+            writable_subtree_ids(conn, actor, id).await?
+        } else {
+            vec![id]
+        };
+
+        let before = fetch_todos_for_update(conn, &ids).await?;
+        check_version(&before, id, expected_version)?;
+
+        let updated = sqlx::query!(
+            "UPDATE todos SET
+                title = COALESCE($1, title),
+                description = COALESCE($2, description),
+                done = COALESCE($3, done),
+                due_at = CASE WHEN $4 THEN $5 ELSE due_at END,
+                priority = COALESCE($6, priority),
+                parent_id = CASE WHEN $7 THEN $8 ELSE parent_id END,
+                version = version + 1
+             WHERE id = $9 AND deleted_at IS NULL
+             RETURNING id",
+            patch.title,
+            patch.description,
+            patch.done,
+            patch.due_at.is_some(),
+            patch.due_at.flatten(),
+            patch.priority as Option<Priority>,
+            patch.parent_id.is_some(),
+            patch.parent_id.flatten(),
+            id
+        )
+        .fetch_optional(&mut *conn)
+        .await?;
+
+        if updated.is_none() {
+            return Ok(None);
+        }
+
+        if cascade {
+            sqlx::query!(
+                "UPDATE todos SET done = true, version = version + 1
+                 WHERE id = ANY($1) AND NOT done",
+                &ids
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+
+        let after = fetch_todos_for_update(conn, &ids).await?;
+        record_events(conn, actor, &before, &after).await?;
+
+        Ok(fetch_todo(&mut *conn, id).await?)
+    }
+
+    async fn delete_in(
+        &self,
+        conn: &mut PgConnection,
+        actor: &Actor,
+        id: i64,
+        expected_version: Option<i64>,
+    ) -> Result<bool, TodoError> {
+        if !permits(access(&mut *conn, actor, id).await?, Access::Owner, id)? {
+            return Ok(false);
+        }
+
+        let ids = live_subtree_ids(conn, id).await?;
+        if ids.is_empty() {
+            return Ok(false);
+        }
+
+        let before = fetch_todos_for_update(conn, &ids).await?;
+        check_version(&before, id, expected_version)?;
+
+        sqlx::query!(
+            "UPDATE todos SET deleted_at = now(), version = version + 1 WHERE id = ANY($1)",
+            &ids
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        let after = fetch_todos_for_update(conn, &ids).await?;
+        record_events(conn, actor, &before, &after).await?;
+
+        Ok(true)
+    }
+
+    async fn apply_in(
+        &self,
+        conn: &mut PgConnection,
+        actor: &Actor,
+        operation: BatchOperation,
+    ) -> Result<BatchOutcome, TodoError> {
+        match operation {
+            BatchOperation::Create(create) => Ok(BatchOutcome::Created(
+                self.create_in(conn, actor, create).await?,
+            )),
+            BatchOperation::Update {
+                id,
+                version,
+                cascade,
+                patch,
+            } => self
+                .update_in(conn, actor, id, PatchTodo { cascade, ..patch }, version)
+                .await?
+                .map(BatchOutcome::Updated)
+                .ok_or(TodoError::NotFound { id }),
+            BatchOperation::Delete { id, version } => {
+                if self.delete_in(conn, actor, id, version).await? {
+                    Ok(BatchOutcome::Deleted)
+                } else {
+                    Err(TodoError::NotFound { id })
+                }
+            }
+        }
+    }
 }
 
 #[async_trait]
 impl TodoRepo for TodoRepoPostgres {
+    async fn user(&self, name: &str) -> Result<Actor, TodoError> {
+        // looking first keeps the sequence from being used up by conflicts
+        let select = || {
+            sqlx::query_as!(Actor, "SELECT id, name FROM users WHERE name = $1", name)
+                .fetch_optional(&self.pool)
+        };
+
+        if let Some(actor) = select().await? {
+            return Ok(actor);
+        }
+
+        sqlx::query!(
+            "INSERT INTO users (name) VALUES ($1) ON CONFLICT (name) DO NOTHING",
+            name
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(select().await?.ok_or_else(|| TodoError::UserNotFound {
+            name: name.to_string(),
+        })?)
+    }
+
+    async fn register(
+        &self,
+        name: &str,
+        email: &str,
+        password_hash: &str,
+    ) -> Result<Account, TodoError> {
+        let account = sqlx::query_as!(
+            Account,
+            r#"INSERT INTO users (name, email, password_hash) VALUES ($1, $2, $3)
+               ON CONFLICT (name) DO NOTHING
+               RETURNING id, name, email AS "email!""#,
+            name,
+            email,
+            password_hash
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        account.ok_or_else(|| TodoError::NameTaken {
+            name: name.to_string(),
+        })
+    }
+
+    async fn credentials(&self, name: &str) -> Result<Option<(Account, String)>, TodoError> {
+        let credentials = sqlx::query!(
+            r#"SELECT id, name, email AS "email!", password_hash AS "password_hash!"
+               FROM users WHERE name = $1 AND password_hash IS NOT NULL"#,
+            name
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(credentials.map(|row| {
+            (
+                Account {
+                    id: row.id,
+                    name: row.name,
+                    email: row.email,
+                },
+                row.password_hash,
+            )
+        }))
+    }
+
+    async fn create_session(
+        &self,
+        user_id: i64,
+        token_hash: &str,
+        expires_at: OffsetDateTime,
+    ) -> Result<(), TodoError> {
+        // clearing out expired sessions here keeps the table from growing
+        sqlx::query!("DELETE FROM sessions WHERE expires_at <= now()")
+            .execute(&self.pool)
+            .await?;
+
+        sqlx::query!(
+            "INSERT INTO sessions (token_hash, user_id, expires_at) VALUES ($1, $2, $3)",
+            token_hash,
+            user_id,
+            expires_at
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn session_account(&self, token_hash: &str) -> Result<Option<Account>, TodoError> {
+        let account = sqlx::query_as!(
+            Account,
+            r#"SELECT users.id, users.name, users.email AS "email!"
+               FROM sessions JOIN users ON users.id = sessions.user_id
+               WHERE token_hash = $1 AND expires_at > now()"#,
+            token_hash
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(account)
+    }
+
+    async fn delete_session(&self, token_hash: &str) -> Result<(), TodoError> {
+        sqlx::query!("DELETE FROM sessions WHERE token_hash = $1", token_hash)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn create_api_key(
+        &self,
+        user: &Actor,
+        name: &str,
+        key_hash: &str,
+        scopes: &[Scope],
+        expires_at: Option<OffsetDateTime>,
+    ) -> Result<ApiKey, TodoError> {
+        let scopes: Vec<String> = scopes
+            .iter()
+            .map(|scope| scope.as_str().to_string())
+            .collect();
+
+        let row = sqlx::query_as!(
+            ApiKeyRow,
+            r#"INSERT INTO api_keys (name, user_id, key_hash, scopes, expires_at)
+               VALUES ($1, $2, $3, $4, $5)
+               RETURNING id, name, user_id, $6 AS "user!", scopes, created_at, expires_at,
+                   last_used_at"#,
+            name,
+            user.id,
+            key_hash,
+            &scopes,
+            expires_at,
+            user.name
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.into())
+    }
+
+    async fn api_keys(&self) -> Result<Vec<ApiKey>, TodoError> {
+        let rows = sqlx::query_as!(
+            ApiKeyRow,
+            r#"SELECT api_keys.id, api_keys.name, user_id, users.name AS user, scopes,
+                   api_keys.created_at, expires_at, last_used_at
+               FROM api_keys JOIN users ON users.id = api_keys.user_id
+               ORDER BY api_keys.id"#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(ApiKey::from).collect())
+    }
+
+    async fn api_key(&self, key_hash: &str) -> Result<Option<ApiKey>, TodoError> {
+        let row = sqlx::query_as!(
+            ApiKeyRow,
+            r#"SELECT api_keys.id, api_keys.name, user_id, users.name AS user, scopes,
+                   api_keys.created_at, expires_at, last_used_at
+               FROM api_keys JOIN users ON users.id = api_keys.user_id
+               WHERE key_hash = $1"#,
+            key_hash
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(ApiKey::from))
+    }
+
+    async fn touch_api_key(&self, id: i64, used_at: OffsetDateTime) -> Result<(), TodoError> {
+        sqlx::query!(
+            "UPDATE api_keys SET last_used_at = $2 WHERE id = $1",
+            id,
+            used_at
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn revoke_api_key(&self, id: i64) -> Result<bool, TodoError> {
+        let result = sqlx::query!("DELETE FROM api_keys WHERE id = $1", id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn list(
+        &self,
+        actor: &Actor,
+        query: &TodoQuery,
+        page: PageRequest,
+    ) -> Result<TodoPage, TodoError> {
+        let mut builder = QueryBuilder::new(TODO_SELECT);
+        push_filters(&mut builder, actor, query);
+
+        if let Some(cursor) = &page.after {
+            builder.push(" AND ");
+            push_after_cursor(&mut builder, &query.sort, cursor);
+        }
+
+        builder.push(" ORDER BY ");
+        let mut order_by = builder.separated(", ");
+        for key in query.sort.keys() {
+            order_by.push(key.field.column());
+            order_by.push_unseparated(if key.descending { " DESC" } else { " ASC" });
+        }
+
+        builder.push(" LIMIT ").push_bind(page.limit + 1);
+
+        let todos = builder
+            .build_query_as::<Todo>()
+            .fetch_all(&self.pool)
+            .await?;
+
+        let total = if page.include_total {
+            let mut builder = QueryBuilder::new("SELECT COUNT(*) FROM todos");
+            push_filters(&mut builder, actor, query);
+
+            let count: i64 = builder.build_query_scalar().fetch_one(&self.pool).await?;
+
+            Some(count)
+        } else {
+            None
+        };
+
+        Ok(TodoPage::from_rows(todos, page.limit, total))
+    }
+
+    async fn create(&self, actor: &Actor, create: CreateTodo) -> Result<Todo, TodoError> {
+        let mut tx = self.pool.begin().await?;
+        let todo = self.create_in(&mut tx, actor, create).await?;
+        tx.commit().await?;
+
+        Ok(todo)
+    }
+
+    async fn get(&self, actor: &Actor, id: i64) -> Result<Option<Todo>, TodoError> {
+        let mut builder = QueryBuilder::new(TODO_SELECT);
+        builder
+            .push(" WHERE deleted_at IS NULL AND id = ")
+            .push_bind(id)
+            .push(" AND ");
+        push_visible(&mut builder, actor);
+
+        Ok(builder
+            .build_query_as::<Todo>()
+            .fetch_optional(&self.pool)
+            .await?)
+    }
+
+    async fn subtree(&self, actor: &Actor, id: i64) -> Result<Option<TodoTree>, TodoError> {
+        let mut builder = QueryBuilder::new(
+            "WITH RECURSIVE subtree AS (
+                SELECT id FROM todos WHERE deleted_at IS NULL AND id = ",
+        );
+        builder.push_bind(id).push(
+            "
+                UNION
+                SELECT todos.id FROM todos JOIN subtree ON todos.parent_id = subtree.id
+                WHERE todos.deleted_at IS NULL
+            ) ",
+        );
+        builder.push(TODO_SELECT);
+        builder.push(" WHERE id IN (SELECT id FROM subtree) AND ");
+        // subtasks that are not shared are left out, along with theirs
+        push_visible(&mut builder, actor);
+        builder.push(" ORDER BY created_at, id");
+
+        let todos = builder
+            .build_query_as::<Todo>()
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(TodoTree::build(id, todos))
+    }
+
+    async fn update(
+        &self,
+        actor: &Actor,
+        id: i64,
+        patch: PatchTodo,
+        expected_version: Option<i64>,
+    ) -> Result<Option<Todo>, TodoError> {
+        let mut tx = self.pool.begin().await?;
+        let todo = self
+            .update_in(&mut tx, actor, id, patch, expected_version)
+            .await?;
+        tx.commit().await?;
+
+        Ok(todo)
+    }
+
+    async fn delete(
+        &self,
+        actor: &Actor,
+        id: i64,
+        expected_version: Option<i64>,
+    ) -> Result<bool, TodoError> {
+        let mut tx = self.pool.begin().await?;
+        let deleted = self.delete_in(&mut tx, actor, id, expected_version).await?;
+        tx.commit().await?;
+
+        Ok(deleted)
+    }
+
+    async fn restore(&self, actor: &Actor, id: i64) -> Result<Option<Todo>, TodoError> {
+        let mut tx = self.pool.begin().await?;
+
+        if !permits(access(&mut *tx, actor, id).await?, Access::Owner, id)? {
+            return Ok(None);
+        }
+
+        let Some(deleted) = sqlx::query!(
+            r#"SELECT deleted_at AS "deleted_at!", parent_id,
+                (SELECT parent.deleted_at IS NOT NULL FROM todos parent
+                 WHERE parent.id = todos.parent_id) AS parent_deleted
+            FROM todos WHERE id = $1 AND deleted_at IS NOT NULL
+            FOR UPDATE"#,
+            id
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            return Ok(None);
+        };
+
+        if let (Some(parent_id), Some(true)) = (deleted.parent_id, deleted.parent_deleted) {
+            return Err(TodoError::ParentDeleted { id, parent_id });
+        }
+
+        // the subtasks deleted along with the todo share its `deleted_at`
+        let ids = sqlx::query_scalar!(
+            r#"WITH RECURSIVE subtree AS (
+                SELECT id FROM todos WHERE id = $1
+                UNION
+                SELECT todos.id FROM todos JOIN subtree ON todos.parent_id = subtree.id
+                WHERE todos.deleted_at = $2
+            )
+            SELECT id AS "id!" FROM subtree"#,
+            id,
+            deleted.deleted_at
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let before = fetch_todos_for_update(&mut tx, &ids).await?;
+
+        sqlx::query!(
+            "UPDATE todos SET deleted_at = NULL, version = version + 1 WHERE id = ANY($1)",
+            &ids
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        let after = fetch_todos_for_update(&mut tx, &ids).await?;
+        record_events(&mut tx, actor, &before, &after).await?;
+
+        let todo = fetch_todo(&mut *tx, id).await?;
+
+        tx.commit().await?;
+
+        Ok(todo)
+    }
+
+    async fn trash(&self, actor: &Actor) -> Result<Vec<Todo>, TodoError> {
+        let mut builder = QueryBuilder::new(TODO_SELECT);
+        builder
+            .push(" WHERE deleted_at IS NOT NULL AND owner_id = ")
+            .push_bind(actor.id)
+            .push(" ORDER BY deleted_at DESC, id");
+
+        let todos = builder
+            .build_query_as::<Todo>()
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(todos)
+    }
+
+    async fn purge(&self, deleted_before: OffsetDateTime) -> Result<u64, TodoError> {
+        let mut tx = self.pool.begin().await?;
+
+        let ids = sqlx::query_scalar!(
+            "SELECT id FROM todos WHERE deleted_at < $1 FOR UPDATE",
+            deleted_before
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        let readers = readers_of(&mut tx, &ids).await?;
+
+        let result = sqlx::query!("DELETE FROM todos WHERE id = ANY($1)", &ids)
+            .execute(&mut *tx)
+            .await?;
+        touch_feeds(&mut tx, &readers).await?;
+
+        tx.commit().await?;
+
+        Ok(result.rows_affected())
+    }
+
+    async fn history(&self, actor: &Actor, id: i64) -> Result<Option<Vec<TodoEvent>>, TodoError> {
+        if access(&self.pool, actor, id).await?.is_none() {
+            return Ok(None);
+        }
+
+        let events = sqlx::query_as!(
+            TodoEvent,
+            r#"SELECT id, todo_id, kind AS "kind: EventKind", actor, created_at, changes
+               FROM todo_events WHERE todo_id = $1 ORDER BY id"#,
+            id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(Some(events))
+    }
+
+    async fn changes_since(
+        &self,
+        actor: &Actor,
+        after: i64,
+        limit: i64,
+    ) -> Result<Vec<TodoChange>, TodoError> {
+        let events = sqlx::query_as!(
+            TodoEvent,
+            r#"SELECT id, todo_id, kind AS "kind: EventKind", actor, created_at, changes
+               FROM todo_events
+               WHERE id > $1 AND todo_id IN (
+                   SELECT id FROM todos WHERE owner_id = $3
+                   UNION
+                   SELECT todo_id FROM todo_shares WHERE user_id = $3
+               )
+               ORDER BY id LIMIT $2"#,
+            after,
+            limit,
+            actor.id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(attach_todos(&self.pool, events).await?)
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<TodoChange> {
+        let receiver = self.changes.subscribe();
+
+        if !self.listening.swap(true, Ordering::SeqCst) {
+            tokio::spawn(listen_for_changes(self.pool.clone(), self.changes.clone()));
+        }
+
+        receiver
+    }
+
+    async fn export(
+        &self,
+        actor: &Actor,
+    ) -> Result<BoxStream<'static, Result<Todo, TodoError>>, TodoError> {
+        // Oldest first, so parents come before the subtasks created under them.
+        let rows = Executor::fetch(
+            &self.pool,
+            sqlx::query(concat!(
+                todo_select!(),
+                " WHERE deleted_at IS NULL AND owner_id = $1 ORDER BY created_at, id"
+            ))
+            .bind(actor.id),
+        );
+
+        Ok(rows.map(|row| Ok(Todo::from_row(&row?)?)).boxed())
+    }
+
+    async fn batch(
+        &self,
+        actor: &Actor,
+        mode: BatchMode,
+        operations: Vec<BatchOperation>,
+    ) -> Result<Vec<BatchOutcome>, TodoError> {
+        let len = operations.len();
+        let mut tx = self.pool.begin().await?;
+        let mut outcomes = Vec::with_capacity(len);
+
+        for operation in operations {
+            // each operation gets a savepoint, so a failed one can be undone
+            // on its own
+            let mut savepoint = Connection::begin(&mut *tx).await?;
+
+            match self.apply_in(&mut savepoint, actor, operation).await {
+                Ok(outcome) => {
+                    savepoint.commit().await?;
+                    outcomes.push(outcome);
+                }
+                Err(error) => {
+                    savepoint.rollback().await?;
+                    outcomes.push(BatchOutcome::Failed(error));
+
+                    if mode == BatchMode::Atomic {
+                        tx.rollback().await?;
+
+                        return Ok(roll_back(outcomes, len));
+                    }
+                }
+            }
+        }
+
+        tx.commit().await?;
+
+        Ok(outcomes)
+    }
+
+    async fn move_todo(
+        &self,
+        actor: &Actor,
+        id: i64,
+        list_id: Option<i64>,
+    ) -> Result<Option<Todo>, TodoError> {
+        let mut tx = self.pool.begin().await?;
+
+        if !permits(access(&mut *tx, actor, id).await?, Access::Owner, id)? {
+            return Ok(None);
+        }
+        if let Some(list_id) = list_id {
+            require_list(&mut *tx, actor, list_id).await?;
+        }
+
+        let before = fetch_todos_for_update(&mut tx, &[id]).await?;
+
+        let moved = sqlx::query!(
+            "UPDATE todos SET list_id = $1, version = version + 1
+             WHERE id = $2 AND deleted_at IS NULL
+             RETURNING id",
+            list_id,
+            id
+        )
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|error| list_not_found(error, list_id))?;
+
+        if moved.is_none() {
+            return Ok(None);
+        }
+
+        let after = fetch_todos_for_update(&mut tx, &[id]).await?;
+        record_events(&mut tx, actor, &before, &after).await?;
+
+        let todo = fetch_todo(&mut *tx, id).await?;
+
+        tx.commit().await?;
+
+        Ok(todo)
+    }
+
+    async fn add_tags(
+        &self,
+        actor: &Actor,
+        id: i64,
+        tags: Vec<String>,
+    ) -> Result<Option<Todo>, TodoError> {
+        let tags = normalize_tags(tags)?;
+
+        let mut tx = self.pool.begin().await?;
+
+        if !permits(access(&mut *tx, actor, id).await?, Access::Write, id)? {
+            return Ok(None);
+        }
+
+        let before = fetch_todos_for_update(&mut tx, &[id]).await?;
+        if !before.iter().any(|todo| todo.deleted_at.is_none()) {
+            return Ok(None);
+        }
+
+        insert_tags(&mut tx, id, &tags).await?;
+        bump_version(&mut tx, id).await?;
+
+        let after = fetch_todos_for_update(&mut tx, &[id]).await?;
+        record_events(&mut tx, actor, &before, &after).await?;
+
+        let todo = fetch_todo(&mut *tx, id).await?;
+
+        tx.commit().await?;
+
+        Ok(todo)
+    }
+
+    async fn remove_tag(
+        &self,
+        actor: &Actor,
+        id: i64,
+        tag: String,
+    ) -> Result<Option<Todo>, TodoError> {
+        let mut tx = self.pool.begin().await?;
+
+        if !permits(access(&mut *tx, actor, id).await?, Access::Write, id)? {
+            return Ok(None);
+        }
+
+        let before = fetch_todos_for_update(&mut tx, &[id]).await?;
+        if !before.iter().any(|todo| todo.deleted_at.is_none()) {
+            return Ok(None);
+        }
+
+        sqlx::query!(
+            "DELETE FROM todo_tags
+             WHERE todo_id = $1 AND tag_id = (SELECT id FROM tags WHERE name = $2)",
+            id,
+            tag.trim()
+        )
+        .execute(&mut *tx)
+        .await?;
+        bump_version(&mut tx, id).await?;
+
+        let after = fetch_todos_for_update(&mut tx, &[id]).await?;
+        record_events(&mut tx, actor, &before, &after).await?;
+
+        let todo = fetch_todo(&mut *tx, id).await?;
+
+        tx.commit().await?;
+
+        Ok(todo)
+    }
+
+    async fn get_tags(&self, actor: &Actor) -> Result<Vec<TagCount>, TodoError> {
+        let tags = sqlx::query_as!(
+            TagCount,
+            r#"SELECT tags.name, COUNT(todos.id) FILTER (WHERE todos.deleted_at IS NULL) AS "count!"
+               FROM tags
+                   JOIN todo_tags ON todo_tags.tag_id = tags.id
+                   JOIN todos ON todos.id = todo_tags.todo_id
+               WHERE todos.owner_id = $1
+                   OR todos.id IN (SELECT todo_id FROM todo_shares WHERE user_id = $1)
+               GROUP BY tags.name
+               ORDER BY tags.name"#,
+            actor.id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(tags)
+    }
+
+    async fn get_lists(&self, actor: &Actor) -> Result<Vec<TodoList>, TodoError> {
+        let lists = sqlx::query_as!(
+            TodoList,
+            "SELECT id, name, created_at, owner_id FROM lists WHERE owner_id = $1
+             ORDER BY created_at, id",
+            actor.id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(lists)
+    }
+
+    async fn create_list(&self, actor: &Actor, name: String) -> Result<TodoList, TodoError> {
+        let list = sqlx::query_as!(
+            TodoList,
+            "INSERT INTO lists (name, owner_id) VALUES ($1, $2)
+             RETURNING id, name, created_at, owner_id",
+            name,
+            actor.id
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(list)
+    }
+
+    async fn get_list(&self, actor: &Actor, id: i64) -> Result<Option<TodoList>, TodoError> {
+        let list = sqlx::query_as!(
+            TodoList,
+            "SELECT id, name, created_at, owner_id FROM lists WHERE id = $1 AND owner_id = $2",
+            id,
+            actor.id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(list)
+    }
+
+    async fn rename_list(
+        &self,
+        actor: &Actor,
+        id: i64,
+        name: String,
+    ) -> Result<Option<TodoList>, TodoError> {
+        let list = sqlx::query_as!(
+            TodoList,
+            "UPDATE lists SET name = $1 WHERE id = $2 AND owner_id = $3
+             RETURNING id, name, created_at, owner_id",
+            name,
+            id,
+            actor.id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(list)
+    }
+
+    async fn delete_list(&self, actor: &Actor, id: i64) -> Result<bool, TodoError> {
+        let mut tx = self.pool.begin().await?;
+
+        let owned = sqlx::query_scalar!(
+            "SELECT id FROM lists WHERE id = $1 AND owner_id = $2 FOR UPDATE",
+            id,
+            actor.id
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        if owned.is_none() {
+            return Ok(false);
+        }
+
+        // The todos in the list, and their subtasks even when they are in
+        // another list, go to the trash. Those already in the trash stay
+        // there, and all of them leave the list here rather than through
+        // `ON DELETE SET NULL`, so that the change is in their history.
+        let ids = sqlx::query_scalar!(
+            r#"WITH RECURSIVE subtree AS (
+                SELECT id FROM todos WHERE list_id = $1
+                UNION
+                SELECT todos.id FROM todos JOIN subtree ON todos.parent_id = subtree.id
+                WHERE todos.deleted_at IS NULL
+            )
+            SELECT id AS "id!" FROM subtree"#,
+            id
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let before = fetch_todos_for_update(&mut tx, &ids).await?;
+
+        sqlx::query!(
+            "UPDATE todos
+             SET deleted_at = COALESCE(deleted_at, now()), list_id = NULLIF(list_id, $2),
+                 version = version + 1
+             WHERE id = ANY($1)",
+            &ids,
+            id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        let after = fetch_todos_for_update(&mut tx, &ids).await?;
+        record_events(&mut tx, actor, &before, &after).await?;
+
+        sqlx::query!("DELETE FROM lists WHERE id = $1", id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(true)
+    }
+
+    async fn due_between(
+        &self,
+        actor: &Actor,
+        from: Option<OffsetDateTime>,
+        to: OffsetDateTime,
+    ) -> Result<Vec<Todo>, TodoError> {
+        let mut builder = QueryBuilder::new(TODO_SELECT);
+        builder.push(" WHERE ");
+        push_visible(&mut builder, actor);
+        builder
+            .push(" AND deleted_at IS NULL AND NOT done AND due_at < ")
+            .push_bind(to);
+
+        if let Some(from) = from {
+            builder.push(" AND due_at >= ").push_bind(from);
+        }
+
+        builder.push(" ORDER BY due_at, id");
+
+        let todos = builder
+            .build_query_as::<Todo>()
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(todos)
+    }
+
+    async fn scheduled(&self, actor: &Actor) -> Result<Vec<Todo>, TodoError> {
+        let mut builder = QueryBuilder::new(TODO_SELECT);
+        builder.push(" WHERE ");
+        push_visible(&mut builder, actor);
+        builder.push(" AND deleted_at IS NULL AND due_at IS NOT NULL ORDER BY due_at, id");
+
+        let todos = builder
+            .build_query_as::<Todo>()
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(todos)
+    }
+
+    async fn last_changed(&self, actor: &Actor) -> Result<Option<OffsetDateTime>, TodoError> {
+        let last_changed = sqlx::query_scalar!(
+            "SELECT changed_at FROM calendar_feeds WHERE user_id = $1",
+            actor.id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(last_changed)
+    }
+
+    async fn calendar_token(&self, actor: &Actor) -> Result<String, TodoError> {
+        sqlx::query!(
+            "INSERT INTO calendar_feeds (user_id, token) VALUES ($1, $2)
+             ON CONFLICT (user_id) DO NOTHING",
+            actor.id,
+            new_token()
+        )
+        .execute(&self.pool)
+        .await?;
+
+        let token = sqlx::query_scalar!(
+            "SELECT token FROM calendar_feeds WHERE user_id = $1",
+            actor.id
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(token)
+    }
+
+    async fn rotate_calendar_token(&self, actor: &Actor) -> Result<String, TodoError> {
+        let token = sqlx::query_scalar!(
+            "INSERT INTO calendar_feeds (user_id, token) VALUES ($1, $2)
+             ON CONFLICT (user_id) DO UPDATE SET token = EXCLUDED.token, created_at = now()
+             RETURNING token",
+            actor.id,
+            new_token()
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(token)
+    }
+
+    async fn calendar_owner(&self, token: &str) -> Result<Option<Actor>, TodoError> {
+        let actor = sqlx::query_as!(
+            Actor,
+            "SELECT users.id, users.name
+             FROM calendar_feeds JOIN users ON users.id = calendar_feeds.user_id
+             WHERE token = $1",
+            token
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(actor)
+    }
+
+    async fn shares(&self, actor: &Actor, id: i64) -> Result<Vec<TodoShare>, TodoError> {
+        require(access(&self.pool, actor, id).await?, Access::Owner, id)?;
+
+        let shares = sqlx::query_as!(
+            TodoShare,
+            r#"SELECT users.name AS user, permission AS "permission: Permission"
+               FROM todo_shares JOIN users ON users.id = todo_shares.user_id
+               WHERE todo_id = $1
+               ORDER BY users.name"#,
+            id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(shares)
+    }
+
+    async fn share(
+        &self,
+        actor: &Actor,
+        id: i64,
+        user: &str,
+        permission: Permission,
+    ) -> Result<TodoShare, TodoError> {
+        require(access(&self.pool, actor, id).await?, Access::Owner, id)?;
+
+        let user_id = find_user(&self.pool, user).await?;
+        if user_id == actor.id {
+            return Err(TodoError::BadRequest {
+                message: "Todos cannot be shared with their owner".to_string(),
+            });
+        }
+
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query!(
+            "INSERT INTO todo_shares (todo_id, user_id, permission) VALUES ($1, $2, $3)
+             ON CONFLICT (todo_id, user_id) DO UPDATE SET permission = EXCLUDED.permission",
+            id,
+            user_id,
+            permission as Permission
+        )
+        .execute(&mut *tx)
+        .await?;
+        touch_feeds(&mut tx, &[user_id]).await?;
+
+        tx.commit().await?;
+
+        Ok(TodoShare {
+            user: user.to_string(),
+            permission,
+        })
+    }
+
+    async fn unshare(&self, actor: &Actor, id: i64, user: &str) -> Result<(), TodoError> {
+        require(access(&self.pool, actor, id).await?, Access::Owner, id)?;
+
+        let user_id = find_user(&self.pool, user).await?;
+
+        let mut tx = self.pool.begin().await?;
+
+        let result = sqlx::query!(
+            "DELETE FROM todo_shares WHERE todo_id = $1 AND user_id = $2",
+            id,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        if result.rows_affected() > 0 {
+            touch_feeds(&mut tx, &[user_id]).await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+}
+
+/// How many changes a subscriber can fall behind by before it has to catch
+/// up from the database.
+const CHANGES_CAPACITY: usize = 1024;
+
+/// How long to wait before reconnecting after losing the `LISTEN` connection.
+const LISTEN_RETRY: std::time::Duration = std::time::Duration::from_secs(1);
+
+// Runs for the lifetime of the app once anyone subscribes, turning the
+// notifications sent for every new history event into changes for the
+// subscribers. Events committed while the connection was down are caught up
+// on after reconnecting, which only needs the last id seen as the ids follow
+// the order of the commits.
+async fn listen_for_changes(pool: Pool<Postgres>, changes: broadcast::Sender<TodoChange>) {
+    let mut last_id: Option<i64> = None;
+
+    loop {
+        let mut listener = match PgListener::connect_with(&pool).await {
+            Ok(listener) => listener,
+            Err(error) => {
+                log(format_args!("Failed to listen for todo changes: {}", error));
+                tokio::time::sleep(LISTEN_RETRY).await;
+                continue;
+            }
+        };
+
+        if let Err(error) = listener.listen("todo_events").await {
+            log(format_args!("Failed to listen for todo changes: {}", error));
+            tokio::time::sleep(LISTEN_RETRY).await;
+            continue;
+        }
+
+        if let Some(after) = last_id {
+            let missed = sqlx::query_as!(
+                TodoEvent,
+                r#"SELECT id, todo_id, kind AS "kind: EventKind", actor, created_at, changes
+                   FROM todo_events WHERE id > $1 ORDER BY id"#,
+                after
+            )
+            .fetch_all(&pool)
+            .await;
+
+            match missed {
+                Ok(missed) => publish(&pool, &changes, missed, &mut last_id).await,
+                Err(error) => log(format_args!(
+                    "Failed to catch up on todo changes: {}",
+                    error
+                )),
+            }
+        }
+
+        // `Ok(None)` means the connection was lost
+        while let Ok(Some(notification)) = listener.try_recv().await {
+            let Ok(id) = notification.payload().parse::<i64>() else {
+                continue;
+            };
+
+            let event = sqlx::query_as!(
+                TodoEvent,
+                r#"SELECT id, todo_id, kind AS "kind: EventKind", actor, created_at, changes
+                   FROM todo_events WHERE id = $1"#,
+                id
+            )
+            .fetch_all(&pool)
+            .await;
+
+            match event {
+                Ok(event) => publish(&pool, &changes, event, &mut last_id).await,
+                Err(error) => log(format_args!("Failed to load todo change {}: {}", id, error)),
+            }
+        }
+    }
+}
+
+async fn publish(
+    pool: &Pool<Postgres>,
+    changes: &broadcast::Sender<TodoChange>,
+    events: Vec<TodoEvent>,
+    last_id: &mut Option<i64>,
+) {
+    match attach_todos(pool, events).await {
+        Ok(published) => {
+            for change in published {
+                *last_id = (*last_id).max(Some(change.event.id));
+
+                // there may be no subscribers left, which is fine
+                let _ = changes.send(change);
+            }
+        }
+        Err(error) => log(format_args!("Failed to load changed todos: {}", error)),
+    }
+}
+
+// Pairs each event with the todo it is about, as it is now, and who can see
+// it. Events of todos that have since been purged are dropped.
+async fn attach_todos(
+    pool: &Pool<Postgres>,
+    events: Vec<TodoEvent>,
+) -> Result<Vec<TodoChange>, sqlx::Error> {
+    let ids: Vec<i64> = events.iter().map(|event| event.todo_id).collect();
+
+    let mut builder = QueryBuilder::new(TODO_SELECT);
+    builder
+        .push(" WHERE id = ANY(")
+        .push_bind(ids.clone())
+        .push(")");
+
+    let todos: HashMap<i64, Todo> = builder
+        .build_query_as::<Todo>()
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|todo| (todo.id, todo))
+        .collect();
+
+    let mut readers: HashMap<i64, Vec<i64>> = HashMap::new();
+    for share in sqlx::query!(
+        "SELECT todo_id, user_id FROM todo_shares WHERE todo_id = ANY($1)",
+        &ids
+    )
+    .fetch_all(pool)
+    .await?
+    {
+        readers
+            .entry(share.todo_id)
+            .or_default()
+            .push(share.user_id);
+    }
+
+    Ok(events
+        .into_iter()
+        .filter_map(|event| {
+            let todo = todos.get(&event.todo_id)?.clone();
+            let mut readers = readers.get(&todo.id).cloned().unwrap_or_default();
+            readers.push(todo.owner_id);
+
+            Some(TodoChange {
+                event,
+                todo,
+                readers,
+            })
+        })
+        .collect())
+}
+
+// An `ApiKey` as stored, with its scopes as text.
+struct ApiKeyRow {
+    id: i64,
+    name: String,
+    user_id: i64,
+    user: String,
+    scopes: Vec<String>,
+    created_at: OffsetDateTime,
+    expires_at: Option<OffsetDateTime>,
+    last_used_at: Option<OffsetDateTime>,
+}
+
+impl From<ApiKeyRow> for ApiKey {
+    fn from(row: ApiKeyRow) -> Self {
+        ApiKey {
+            id: row.id,
+            name: row.name,
+            user_id: row.user_id,
+            user: row.user,
+            // only scopes that parsed were ever stored
+            scopes: row
+                .scopes
+                .iter()
+                .filter_map(|scope| scope.parse().ok())
+                .collect(),
+            created_at: row.created_at,
+            expires_at: row.expires_at,
+            last_used_at: row.last_used_at,
+        }
+    }
+}
 
-}
\ No newline at end of file
+// 32 random bytes, which is long enough that feed URLs, session cookies and
+// API keys cannot be guessed.
+pub(crate) fn new_token() -> String {
+    let bytes: [u8; 32] = rand::random();
+
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
+
+///
+/// What a user can do with a todo, from least to most.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Access {
+    Read,
+    Write,
+    Owner,
+}
+
+impl From<Permission> for Access {
+    fn from(permission: Permission) -> Self {
+        match permission {
+            Permission::Read => Access::Read,
+            Permission::Write => Access::Write,
+        }
+    }
+}
+
+// Whether the actor can see the todo, failing if they can but have less than
+// the `needed` access to it. A todo they cannot see at all is treated as
+// missing, so that ids do not leak.
+fn permits(access: Option<Access>, needed: Access, id: i64) -> Result<bool, TodoError> {
+    match access {
+        None => Ok(false),
+        Some(access) if access < needed => Err(TodoError::Forbidden { id }),
+        Some(_) => Ok(true),
+    }
+}
+
+fn require(access: Option<Access>, needed: Access, id: i64) -> Result<(), TodoError> {
+    match permits(access, needed, id)? {
+        true => Ok(()),
+        false => Err(TodoError::NotFound { id }),
+    }
+}
+
+// Subtasks always belong to the owner of their parent.
+fn require_parent(access: Option<Access>, parent_id: i64) -> Result<(), TodoError> {
+    require(access, Access::Owner, parent_id).map_err(|error| match error {
+        TodoError::NotFound { id } => TodoError::ParentNotFound { id },
+        error => error,
+    })
+}
+
+// The access of the actor to the todo, which may be in the trash.
+async fn access<'e>(
+    executor: impl PgExecutor<'e>,
+    actor: &Actor,
+    id: i64,
+) -> Result<Option<Access>, sqlx::Error> {
+    let todo = sqlx::query!(
+        r#"SELECT owner_id,
+            (SELECT permission FROM todo_shares WHERE todo_id = todos.id AND user_id = $2)
+                AS "permission: Permission"
+        FROM todos WHERE id = $1"#,
+        id,
+        actor.id
+    )
+    .fetch_optional(executor)
+    .await?;
+
+    Ok(todo.and_then(|todo| {
+        if todo.owner_id == actor.id {
+            Some(Access::Owner)
+        } else {
+            todo.permission.map(Access::from)
+        }
+    }))
+}
+
+// Lists of other users might as well not exist.
+async fn require_list<'e>(
+    executor: impl PgExecutor<'e>,
+    actor: &Actor,
+    id: i64,
+) -> Result<(), TodoError> {
+    let owned = sqlx::query_scalar!(
+        r#"SELECT EXISTS (SELECT 1 FROM lists WHERE id = $1 AND owner_id = $2) AS "owned!""#,
+        id,
+        actor.id
+    )
+    .fetch_one(executor)
+    .await?;
+
+    if owned {
+        Ok(())
+    } else {
+        Err(TodoError::ListNotFound { id })
+    }
+}
+
+async fn find_user<'e>(executor: impl PgExecutor<'e>, name: &str) -> Result<i64, TodoError> {
+    sqlx::query_scalar!("SELECT id FROM users WHERE name = $1", name)
+        .fetch_optional(executor)
+        .await?
+        .ok_or_else(|| TodoError::UserNotFound {
+            name: name.to_string(),
+        })
+}
+
+// Limits a query on `todos` to the todos the actor owns or that are shared
+// with them.
+fn push_visible(builder: &mut QueryBuilder<'_, Postgres>, actor: &Actor) {
+    builder
+        .push("(owner_id = ")
+        .push_bind(actor.id)
+        .push(" OR id IN (SELECT todo_id FROM todo_shares WHERE user_id = ")
+        .push_bind(actor.id)
+        .push("))");
+}
+
+// Turns the outcomes of an atomic batch that failed part way through into
+// its final outcomes: everything but the failure is rolled back, including
+// the operations that never ran.
+fn roll_back(outcomes: Vec<BatchOutcome>, len: usize) -> Vec<BatchOutcome> {
+    let mut outcomes: Vec<BatchOutcome> = outcomes
+        .into_iter()
+        .map(|outcome| match outcome {
+            failed @ BatchOutcome::Failed(_) => failed,
+            _ => BatchOutcome::RolledBack,
+        })
+        .collect();
+    outcomes.resize_with(len, || BatchOutcome::RolledBack);
+
+    outcomes
+}
+
+// The todo and its subtasks, leaving out anything in the trash.
+async fn live_subtree_ids(conn: &mut PgConnection, id: i64) -> Result<Vec<i64>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"WITH RECURSIVE subtree AS (
+            SELECT id FROM todos WHERE id = $1 AND deleted_at IS NULL
+            UNION
+            SELECT todos.id FROM todos JOIN subtree ON todos.parent_id = subtree.id
+            WHERE todos.deleted_at IS NULL
+        )
+        SELECT id AS "id!" FROM subtree"#,
+        id
+    )
+    .fetch_all(conn)
+    .await
+}
+
+// The todo and those of its live subtasks the actor can change. Sharing a
+// todo does not share its subtasks, so completing it with `cascade` leaves
+// alone the ones a user it is shared with cannot change.
+async fn writable_subtree_ids(
+    conn: &mut PgConnection,
+    actor: &Actor,
+    id: i64,
+) -> Result<Vec<i64>, sqlx::Error> {
+    let ids = live_subtree_ids(&mut *conn, id).await?;
+
+    sqlx::query_scalar!(
+        "SELECT id FROM todos
+         WHERE id = ANY($1) AND (owner_id = $2 OR id IN (
+             SELECT todo_id FROM todo_shares WHERE user_id = $2 AND permission = 'write'
+         ))",
+        &ids,
+        actor.id
+    )
+    .fetch_all(conn)
+    .await
+}
+
+// The todos with the given ids, including those in the trash, locked for the
+// rest of the transaction so their history lines up with what was written.
+async fn fetch_todos_for_update(
+    conn: &mut PgConnection,
+    ids: &[i64],
+) -> Result<Vec<Todo>, sqlx::Error> {
+    let mut builder = QueryBuilder::new(TODO_SELECT);
+    builder
+        .push(" WHERE id = ANY(")
+        .push_bind(ids.to_vec())
+        .push(") ORDER BY id FOR UPDATE");
+
+    builder.build_query_as::<Todo>().fetch_all(conn).await
+}
+
+// Marks a change that does not touch the `todos` row itself, such as to the
+// tags of the todo.
+async fn bump_version(conn: &mut PgConnection, id: i64) -> Result<(), sqlx::Error> {
+    sqlx::query!("UPDATE todos SET version = version + 1 WHERE id = $1", id)
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+// Fails if the todo with the given id, as read before a change, is not at
+// the version the caller expects.
+fn check_version(before: &[Todo], id: i64, expected_version: Option<i64>) -> Result<(), TodoError> {
+    let current = before
+        .iter()
+        .find(|todo| todo.id == id && todo.deleted_at.is_none());
+
+    match (current, expected_version) {
+        (Some(todo), Some(expected)) if todo.version != expected => {
+            Err(TodoError::VersionMismatch { id })
+        }
+        _ => Ok(()),
+    }
+}
+
+// Adds an event to the history of every todo that differs between `before`
+// and `after`.
+async fn record_events(
+    conn: &mut PgConnection,
+    actor: &Actor,
+    before: &[Todo],
+    after: &[Todo],
+) -> Result<(), sqlx::Error> {
+    let mut changed = Vec::new();
+
+    for todo in after {
+        let previous = before.iter().find(|previous| previous.id == todo.id);
+
+        if let Some((kind, changes)) = EventKind::of_change(previous, todo) {
+            sqlx::query!(
+                "INSERT INTO todo_events (todo_id, kind, actor, changes) VALUES ($1, $2, $3, $4)",
+                todo.id,
+                kind as EventKind,
+                actor.name,
+                changes
+            )
+            .execute(&mut *conn)
+            .await?;
+
+            changed.push(todo.id);
+        }
+    }
+
+    if !changed.is_empty() {
+        let readers = readers_of(conn, &changed).await?;
+        touch_feeds(conn, &readers).await?;
+    }
+
+    Ok(())
+}
+
+// The owners of the todos and the users they are shared with.
+async fn readers_of(conn: &mut PgConnection, ids: &[i64]) -> Result<Vec<i64>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"SELECT owner_id AS "id!" FROM todos WHERE id = ANY($1)
+           UNION
+           SELECT user_id FROM todo_shares WHERE todo_id = ANY($1)"#,
+        ids
+    )
+    .fetch_all(conn)
+    .await
+}
+
+// Moves on the `Last-Modified` of the calendar feeds of the users, by at
+// least a second so that a client that fetched a feed earlier in the same
+// second is not told it is up to date. The feeds are locked in order so that
+// concurrent changes cannot deadlock on them.
+async fn touch_feeds(conn: &mut PgConnection, user_ids: &[i64]) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE calendar_feeds
+         SET changed_at = GREATEST(
+             date_trunc('second', changed_at) + interval '1 second',
+             clock_timestamp()
+         )
+         WHERE user_id IN (
+             SELECT user_id FROM calendar_feeds WHERE user_id = ANY($1)
+             ORDER BY user_id FOR UPDATE
+         )",
+        user_ids
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+// Serialises changes to the shape of the todo tree for the rest of the
+// transaction, so two concurrent moves cannot each pass `check_parent` and
+// together form a cycle.
+async fn lock_hierarchy(conn: &mut PgConnection) -> Result<(), sqlx::Error> {
+    const TODO_HIERARCHY_LOCK: i64 = 0x746f_646f_7472_6565;
+
+    sqlx::query("SELECT pg_advisory_xact_lock($1)")
+        .bind(TODO_HIERARCHY_LOCK)
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+// Checks that `parent_id` exists and can take the todo `id` (or a new todo,
+// for `None`) as a subtask without forming a cycle or exceeding `max_depth`.
+async fn check_parent(
+    conn: &mut PgConnection,
+    id: Option<i64>,
+    parent_id: i64,
+    max_depth: usize,
+) -> Result<(), TodoError> {
+    // the parent followed by each of its ancestors up to the root
+    let ancestors = sqlx::query_scalar!(
+        r#"WITH RECURSIVE ancestors AS (
+            SELECT id, parent_id, 1 AS depth FROM todos WHERE id = $1 AND deleted_at IS NULL
+            UNION ALL
+            SELECT todos.id, todos.parent_id, ancestors.depth + 1
+            FROM todos JOIN ancestors ON todos.id = ancestors.parent_id
+            WHERE ancestors.depth <= $2
+        )
+        SELECT id AS "id!" FROM ancestors"#,
+        parent_id,
+        max_depth as i32
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    if ancestors.is_empty() {
+        return Err(TodoError::ParentNotFound { id: parent_id });
+    }
+
+    let height = match id {
+        Some(id) if ancestors.contains(&id) => {
+            return Err(TodoError::Cycle { id, parent_id });
+        }
+        Some(id) => {
+            sqlx::query_scalar!(
+                r#"WITH RECURSIVE subtree AS (
+                SELECT id, 0 AS depth FROM todos WHERE id = $1
+                UNION ALL
+                SELECT todos.id, subtree.depth + 1
+                FROM todos JOIN subtree ON todos.parent_id = subtree.id
+                WHERE subtree.depth <= $2
+            )
+            SELECT COALESCE(MAX(depth), 0) AS "height!" FROM subtree"#,
+                id,
+                max_depth as i32
+            )
+            .fetch_one(&mut *conn)
+            .await? as usize
+        }
+        None => 0,
+    };
+
+    if ancestors.len() + height > max_depth {
+        return Err(TodoError::TooDeep { max_depth });
+    }
+
+    Ok(())
+}
+
+// Every column of `Todo`, for queries built at runtime.
+const TODO_SELECT: &str = todo_select!();
+
+async fn fetch_todo<'e>(
+    executor: impl PgExecutor<'e>,
+    id: i64,
+) -> Result<Option<Todo>, sqlx::Error> {
+    sqlx::query_as!(
+        Todo,
+        r#"SELECT id, title, description, done, created_at, list_id,
+            ARRAY(SELECT tags.name FROM todo_tags JOIN tags ON tags.id = todo_tags.tag_id
+                  WHERE todo_tags.todo_id = todos.id ORDER BY tags.name) AS "tags!",
+            due_at, priority AS "priority: Priority", completed_at, parent_id, deleted_at,
+            version, owner_id
+        FROM todos WHERE id = $1 AND deleted_at IS NULL"#,
+        id
+    )
+    .fetch_optional(executor)
+    .await
+}
+
+async fn insert_tags(
+    conn: &mut PgConnection,
+    todo_id: i64,
+    tags: &[String],
+) -> Result<(), sqlx::Error> {
+    if tags.is_empty() {
+        return Ok(());
+    }
+
+    sqlx::query!(
+        "INSERT INTO tags (name) SELECT * FROM UNNEST($1::TEXT[]) ON CONFLICT (name) DO NOTHING",
+        tags
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query!(
+        "INSERT INTO todo_tags (todo_id, tag_id) SELECT $1, id FROM tags WHERE name = ANY($2)
+         ON CONFLICT DO NOTHING",
+        todo_id,
+        tags
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+// A todo pointing at a list that does not exist violates `todos_list_id_fkey`.
+fn list_not_found(error: sqlx::Error, list_id: Option<i64>) -> TodoError {
+    match (&error, list_id) {
+        (sqlx::Error::Database(database_error), Some(id))
+            if database_error.constraint() == Some("todos_list_id_fkey") =>
+        {
+            TodoError::ListNotFound { id }
+        }
+        _ => error.into(),
+    }
+}
+
+// Appends a `WHERE` clause for the filters, so that callers can always go on
+// with ` AND ...`.
+fn push_filters(builder: &mut QueryBuilder<'_, Postgres>, actor: &Actor, query: &TodoQuery) {
+    builder.push(" WHERE deleted_at IS NULL AND ");
+    push_visible(builder, actor);
+
+    if let Some(done) = query.done {
+        builder.push(" AND done = ").push_bind(done);
+    }
+
+    if let Some(created_after) = query.created_after {
+        builder.push(" AND created_at > ").push_bind(created_after);
+    }
+
+    if let Some(created_before) = query.created_before {
+        builder.push(" AND created_at < ").push_bind(created_before);
+    }
+
+    if let Some(list_id) = query.list_id {
+        builder.push(" AND list_id = ").push_bind(list_id);
+    }
+
+    if !query.tags.is_empty() {
+        let matching_tags =
+            "(SELECT COUNT(*) FROM todo_tags JOIN tags ON tags.id = todo_tags.tag_id
+              WHERE todo_tags.todo_id = todos.id AND tags.name = ANY(";
+
+        builder
+            .push(" AND ")
+            .push(matching_tags)
+            .push_bind(query.tags.clone());
+
+        match query.tag_match {
+            TagMatch::All => builder.push(")) = ").push_bind(query.tags.len() as i64),
+            TagMatch::Any => builder.push(")) > 0"),
+        };
+    }
+
+    if let Some(q) = &query.q {
+        let pattern = format!("%{}%", escape_like(q));
+
+        builder
+            .push(" AND (title ILIKE ")
+            .push_bind(pattern.clone())
+            .push(" OR description ILIKE ")
+            .push_bind(pattern)
+            .push(")");
+    }
+}
+
+fn escape_like(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+// Keyset condition for "sorts after the cursor": for sort keys k1..kn this is
+// (k1 > v1) OR (k1 = v1 AND k2 > v2) OR ..., with `<` for descending keys.
+fn push_after_cursor(
+    builder: &mut QueryBuilder<'_, Postgres>,
+    sort: &TodoSort,
+    cursor: &TodoCursor,
+) {
+    let keys = sort.keys();
+
+    builder.push("(");
+    for (index, key) in keys.iter().enumerate() {
+        if index > 0 {
+            builder.push(" OR ");
+        }
+
+        builder.push("(");
+        for equal in &keys[..index] {
+            builder.push(equal.field.column()).push(" = ");
+            push_cursor_value(builder, equal.field, cursor);
+            builder.push(" AND ");
+        }
+
+        builder
+            .push(key.field.column())
+            .push(if key.descending { " < " } else { " > " });
+        push_cursor_value(builder, key.field, cursor);
+        builder.push(")");
+    }
+    builder.push(")");
+}
+
+fn push_cursor_value(
+    builder: &mut QueryBuilder<'_, Postgres>,
+    field: SortField,
+    cursor: &TodoCursor,
+) {
+    match field {
+        SortField::CreatedAt => builder.push_bind(cursor.created_at),
+        SortField::Title => builder.push_bind(cursor.title.clone()),
+        SortField::Done => builder.push_bind(cursor.done),
+        SortField::Id => builder.push_bind(cursor.id),
+    };
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct InMemoryTodoRepo {
+    todos: Arc<Mutex<BTreeMap<i64, Todo>>>,
+    counter: Arc<Mutex<i64>>,
+    lists: Arc<Mutex<BTreeMap<i64, TodoList>>>,
+    list_counter: Arc<Mutex<i64>>,
+    tags: Arc<Mutex<BTreeSet<String>>>,
+    events: Arc<Mutex<Vec<TodoEvent>>>,
+    event_counter: Arc<Mutex<i64>>,
+    changes: broadcast::Sender<TodoChange>,
+    /// User names by id.
+    users: Arc<Mutex<BTreeMap<i64, String>>>,
+    /// What each user a todo is shared with can do, by todo and user id.
+    shares: Arc<Mutex<BTreeMap<(i64, i64), Permission>>>,
+    /// The token of each calendar feed and when the feed last changed, by
+    /// user id.
+    calendar_feeds: Arc<Mutex<HashMap<i64, (String, OffsetDateTime)>>>,
+    /// The email and password hash of the users with an account, by user id.
+    accounts: Arc<Mutex<HashMap<i64, (String, String)>>>,
+    /// The user id and expiry of each session, by the hash of its token.
+    sessions: Arc<Mutex<HashMap<String, (i64, OffsetDateTime)>>>,
+    /// API keys along with the hash of each key, by id.
+    api_keys: Arc<Mutex<BTreeMap<i64, (String, ApiKey)>>>,
+    api_key_counter: Arc<Mutex<i64>>,
+    max_depth: usize,
+}
+
+impl Default for InMemoryTodoRepo {
+    fn default() -> Self {
+        Self {
+            todos: Default::default(),
+            counter: Default::default(),
+            lists: Default::default(),
+            list_counter: Default::default(),
+            tags: Default::default(),
+            events: Default::default(),
+            event_counter: Default::default(),
+            changes: broadcast::channel(CHANGES_CAPACITY).0,
+            users: Arc::new(Mutex::new(BTreeMap::from([(
+                ANONYMOUS_USER_ID,
+                Actor::default().name,
+            )]))),
+            shares: Default::default(),
+            calendar_feeds: Default::default(),
+            accounts: Default::default(),
+            sessions: Default::default(),
+            api_keys: Default::default(),
+            api_key_counter: Default::default(),
+            max_depth: DEFAULT_MAX_DEPTH,
+        }
+    }
+}
+
+impl InMemoryTodoRepo {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn with_max_depth(self, max_depth: usize) -> Self {
+        Self { max_depth, ..self }
+    }
+
+    async fn require_list(&self, actor: &Actor, id: i64) -> Result<(), TodoError> {
+        let lists = self.lists.lock().await;
+
+        match lists.get(&id) {
+            Some(list) if list.owner_id == actor.id => Ok(()),
+            _ => Err(TodoError::ListNotFound { id }),
+        }
+    }
+
+    async fn find_user(&self, name: &str) -> Result<i64, TodoError> {
+        let users = self.users.lock().await;
+
+        users
+            .iter()
+            .find(|(_, user)| user.as_str() == name)
+            .map(|(&id, _)| id)
+            .ok_or_else(|| TodoError::UserNotFound {
+                name: name.to_string(),
+            })
+    }
+
+    // The in-memory counterpart of `access`.
+    async fn access(&self, todos: &BTreeMap<i64, Todo>, actor: &Actor, id: i64) -> Option<Access> {
+        let todo = todos.get(&id)?;
+        if todo.owner_id == actor.id {
+            return Some(Access::Owner);
+        }
+
+        let shares = self.shares.lock().await;
+
+        shares.get(&(id, actor.id)).copied().map(Access::from)
+    }
+
+    // The ids of the todos shared with the actor, for use with `can_see`.
+    async fn shared_with(&self, actor: &Actor) -> BTreeSet<i64> {
+        let shares = self.shares.lock().await;
+
+        shares
+            .keys()
+            .filter(|(_, user_id)| *user_id == actor.id)
+            .map(|(todo_id, _)| *todo_id)
+            .collect()
+    }
+
+    // The in-memory counterpart of `touch_feeds`.
+    async fn touch_feeds(&self, user_ids: &[i64]) {
+        let mut feeds = self.calendar_feeds.lock().await;
+
+        for user_id in user_ids {
+            if let Some((_, changed_at)) = feeds.get_mut(user_id) {
+                let next_second =
+                    changed_at.replace_nanosecond(0).unwrap() + time::Duration::SECOND;
+                *changed_at = next_second.max(OffsetDateTime::now_utc());
+            }
+        }
+    }
+
+    // The in-memory counterpart of `record_events`.
+    async fn record_events(&self, actor: &Actor, before: &[Todo], after: &[Todo]) {
+        let mut events = self.events.lock().await;
+        let mut counter = self.event_counter.lock().await;
+        let shares = self.shares.lock().await;
+
+        for todo in after {
+            let previous = before.iter().find(|previous| previous.id == todo.id);
+
+            if let Some((kind, changes)) = EventKind::of_change(previous, todo) {
+                *counter += 1;
+
+                let event = TodoEvent {
+                    id: *counter,
+                    todo_id: todo.id,
+                    kind,
+                    actor: actor.name.clone(),
+                    created_at: OffsetDateTime::now_utc(),
+                    changes,
+                };
+                events.push(event.clone());
+
+                let readers = readers(&shares, todo);
+                self.touch_feeds(&readers).await;
+
+                let _ = self.changes.send(TodoChange {
+                    event,
+                    todo: todo.clone(),
+                    readers,
+                });
+            }
+        }
+    }
+}
+
+impl InMemoryTodoRepo {
+    // Unlike a Postgres savepoint this does not hold off other writers, or
+    // take back changes already sent to subscribers, which is fine for a
+    // repository that only backs tests. Ids are not handed out again after a
+    // rollback, as with a Postgres sequence.
+    async fn savepoint(&self) -> InMemorySavepoint {
+        InMemorySavepoint {
+            todos: self.todos.lock().await.clone(),
+            tags: self.tags.lock().await.clone(),
+            events: self.events.lock().await.clone(),
+        }
+    }
+
+    async fn roll_back_to(&self, savepoint: InMemorySavepoint) {
+        *self.todos.lock().await = savepoint.todos;
+        *self.tags.lock().await = savepoint.tags;
+        *self.events.lock().await = savepoint.events;
+    }
+
+    async fn apply(
+        &self,
+        actor: &Actor,
+        operation: BatchOperation,
+    ) -> Result<BatchOutcome, TodoError> {
+        match operation {
+            BatchOperation::Create(create) => {
+                Ok(BatchOutcome::Created(self.create(actor, create).await?))
+            }
+            BatchOperation::Update {
+                id,
+                version,
+                cascade,
+                patch,
+            } => self
+                .update(actor, id, PatchTodo { cascade, ..patch }, version)
+                .await?
+                .map(BatchOutcome::Updated)
+                .ok_or(TodoError::NotFound { id }),
+            BatchOperation::Delete { id, version } => {
+                if self.delete(actor, id, version).await? {
+                    Ok(BatchOutcome::Deleted)
+                } else {
+                    Err(TodoError::NotFound { id })
+                }
+            }
+        }
+    }
+}
+
+struct InMemorySavepoint {
+    todos: BTreeMap<i64, Todo>,
+    tags: BTreeSet<String>,
+    events: Vec<TodoEvent>,
+}
+
+fn snapshot(todos: &BTreeMap<i64, Todo>, ids: &[i64]) -> Vec<Todo> {
+    ids.iter().filter_map(|id| todos.get(id).cloned()).collect()
+}
+
+#[async_trait]
+impl TodoRepo for InMemoryTodoRepo {
+    async fn user(&self, name: &str) -> Result<Actor, TodoError> {
+        let mut users = self.users.lock().await;
+
+        let id = match users.iter().find(|(_, user)| user.as_str() == name) {
+            Some((&id, _)) => id,
+            None => {
+                let id = users.keys().last().map_or(1, |id| id + 1);
+                users.insert(id, name.to_string());
+                id
+            }
+        };
+
+        Ok(Actor {
+            id,
+            name: name.to_string(),
+        })
+    }
+
+    async fn register(
+        &self,
+        name: &str,
+        email: &str,
+        password_hash: &str,
+    ) -> Result<Account, TodoError> {
+        let mut users = self.users.lock().await;
+
+        if users.values().any(|user| user == name) {
+            return Err(TodoError::NameTaken {
+                name: name.to_string(),
+            });
+        }
+        let id = users.keys().last().map_or(1, |id| id + 1);
+        users.insert(id, name.to_string());
+
+        self.accounts
+            .lock()
+            .await
+            .insert(id, (email.to_string(), password_hash.to_string()));
+
+        Ok(Account {
+            id,
+            name: name.to_string(),
+            email: email.to_string(),
+        })
+    }
+
+    async fn credentials(&self, name: &str) -> Result<Option<(Account, String)>, TodoError> {
+        let Ok(id) = self.find_user(name).await else {
+            return Ok(None);
+        };
+        let accounts = self.accounts.lock().await;
+
+        Ok(accounts.get(&id).map(|(email, password_hash)| {
+            (
+                Account {
+                    id,
+                    name: name.to_string(),
+                    email: email.clone(),
+                },
+                password_hash.clone(),
+            )
+        }))
+    }
+
+    async fn create_session(
+        &self,
+        user_id: i64,
+        token_hash: &str,
+        expires_at: OffsetDateTime,
+    ) -> Result<(), TodoError> {
+        let mut sessions = self.sessions.lock().await;
+
+        let now = OffsetDateTime::now_utc();
+        sessions.retain(|_, (_, expires_at)| *expires_at > now);
+        sessions.insert(token_hash.to_string(), (user_id, expires_at));
+
+        Ok(())
+    }
+
+    async fn session_account(&self, token_hash: &str) -> Result<Option<Account>, TodoError> {
+        let sessions = self.sessions.lock().await;
+        let Some(&(id, expires_at)) = sessions.get(token_hash) else {
+            return Ok(None);
+        };
+        if expires_at <= OffsetDateTime::now_utc() {
+            return Ok(None);
+        }
+
+        let users = self.users.lock().await;
+        let accounts = self.accounts.lock().await;
+
+        Ok(users
+            .get(&id)
+            .zip(accounts.get(&id))
+            .map(|(name, (email, _))| Account {
+                id,
+                name: name.clone(),
+                email: email.clone(),
+            }))
+    }
+
+    async fn delete_session(&self, token_hash: &str) -> Result<(), TodoError> {
+        self.sessions.lock().await.remove(token_hash);
+
+        Ok(())
+    }
+
+    async fn create_api_key(
+        &self,
+        user: &Actor,
+        name: &str,
+        key_hash: &str,
+        scopes: &[Scope],
+        expires_at: Option<OffsetDateTime>,
+    ) -> Result<ApiKey, TodoError> {
+        let mut api_keys = self.api_keys.lock().await;
+
+        let id = {
+            let mut counter_guard = self.api_key_counter.lock().await;
+            *counter_guard += 1;
+            *counter_guard
+        };
+        let api_key = ApiKey {
+            id,
+            name: name.to_string(),
+            user_id: user.id,
+            user: user.name.clone(),
+            scopes: scopes.to_vec(),
+            created_at: OffsetDateTime::now_utc(),
+            expires_at,
+            last_used_at: None,
+        };
+        api_keys.insert(id, (key_hash.to_string(), api_key.clone()));
+
+        Ok(api_key)
+    }
+
+    async fn api_keys(&self) -> Result<Vec<ApiKey>, TodoError> {
+        let api_keys = self.api_keys.lock().await;
+
+        Ok(api_keys
+            .values()
+            .map(|(_, api_key)| api_key.clone())
+            .collect())
+    }
+
+    async fn api_key(&self, key_hash: &str) -> Result<Option<ApiKey>, TodoError> {
+        let api_keys = self.api_keys.lock().await;
+
+        Ok(api_keys
+            .values()
+            .find(|(hash, _)| hash == key_hash)
+            .map(|(_, api_key)| api_key.clone()))
+    }
+
+    async fn touch_api_key(&self, id: i64, used_at: OffsetDateTime) -> Result<(), TodoError> {
+        if let Some((_, api_key)) = self.api_keys.lock().await.get_mut(&id) {
+            api_key.last_used_at = Some(used_at);
+        }
+
+        Ok(())
+    }
+
+    async fn revoke_api_key(&self, id: i64) -> Result<bool, TodoError> {
+        Ok(self.api_keys.lock().await.remove(&id).is_some())
+    }
+
+    async fn list(
+        &self,
+        actor: &Actor,
+        query: &TodoQuery,
+        page: PageRequest,
+    ) -> Result<TodoPage, TodoError> {
+        let shared = self.shared_with(actor).await;
+        let guard = self.todos.lock().await;
+
+        let mut todos: Vec<Todo> = guard
+            .values()
+            .filter(|todo| todo.deleted_at.is_none() && can_see(todo, actor, &shared))
+            .filter(|todo| query.matches(todo))
+            .cloned()
+            .collect();
+        todos.sort_by(|left, right| {
+            query
+                .sort
+                .compare(&TodoCursor::of(left), &TodoCursor::of(right))
+        });
+
+        let total = page.include_total.then_some(todos.len() as i64);
+
+        let todos = todos
+            .into_iter()
+            .filter(|todo| match &page.after {
+                Some(cursor) => query.sort.compare(&TodoCursor::of(todo), cursor).is_gt(),
+                None => true,
+            })
+            .take(page.limit as usize + 1)
+            .collect();
+
+        Ok(TodoPage::from_rows(todos, page.limit, total))
+    }
+
+    async fn create(&self, actor: &Actor, create: CreateTodo) -> Result<Todo, TodoError> {
+        check_rules(&create)?;
+        let tags = normalize_tags(create.tags)?;
+
+        if let Some(list_id) = create.list_id {
+            self.require_list(actor, list_id).await?;
+        }
+
+        let mut guard = self.todos.lock().await;
+
+        if let Some(parent_id) = create.parent_id {
+            require_parent(self.access(&guard, actor, parent_id).await, parent_id)?;
+            check_parent_in(&guard, None, parent_id, self.max_depth)?;
+        }
+
+        self.tags.lock().await.extend(tags.iter().cloned());
+
+        let id = {
+            let mut counter_guard = self.counter.lock().await;
+            *counter_guard += 1;
+            *counter_guard
+        };
+
+        let mut todo = Todo {
+            id,
+            title: create.title,
+            description: create.description,
+            done: false,
+            created_at: OffsetDateTime::now_utc(),
+            list_id: create.list_id,
+            tags,
+            due_at: create.due_at,
+            priority: create.priority,
+            completed_at: None,
+            parent_id: create.parent_id,
+            deleted_at: None,
+            version: 1,
+            owner_id: actor.id,
+        };
+        set_done(&mut todo, create.done);
+        guard.insert(id, todo.clone());
+
+        self.record_events(actor, &[], std::slice::from_ref(&todo))
+            .await;
+
+        Ok(todo)
+    }
+
+    async fn get(&self, actor: &Actor, id: i64) -> Result<Option<Todo>, TodoError> {
+        let guard = self.todos.lock().await;
+
+        if self.access(&guard, actor, id).await.is_none() {
+            return Ok(None);
+        }
+
+        Ok(live(&guard, id).cloned())
+    }
+
+    async fn subtree(&self, actor: &Actor, id: i64) -> Result<Option<TodoTree>, TodoError> {
+        let shared = self.shared_with(actor).await;
+        let guard = self.todos.lock().await;
+
+        let mut todos: Vec<Todo> = subtree_ids(&guard, id, is_live)
+            .iter()
+            .filter_map(|id| guard.get(id).cloned())
+            .filter(|todo| can_see(todo, actor, &shared))
+            .collect();
+        todos.sort_by_key(|todo| (todo.created_at, todo.id));
+
+        Ok(TodoTree::build(id, todos))
+    }
+
+    async fn update(
+        &self,
+        actor: &Actor,
+        id: i64,
+        patch: PatchTodo,
+        expected_version: Option<i64>,
+    ) -> Result<Option<Todo>, TodoError> {
+        check_rules(&patch)?;
+
+        let mut guard = self.todos.lock().await;
+
+        let needed = match patch.parent_id {
+            Some(_) => Access::Owner,
+            None => Access::Write,
+        };
+        if !permits(self.access(&guard, actor, id).await, needed, id)? {
+            return Ok(None);
+        }
+
+        if live(&guard, id).is_none() {
+            return Ok(None);
+        }
+
+        if let Some(Some(parent_id)) = patch.parent_id {
+            require_parent(self.access(&guard, actor, parent_id).await, parent_id)?;
+            check_parent_in(&guard, Some(id), parent_id, self.max_depth)?;
+        }
+
+        let cascade = patch.cascade && patch.done == Some(true);
+        let mut ids = vec![id];
+        if cascade {
+            // only the subtasks the actor can change, as with Postgres
+            for subtask_id in subtree_ids(&guard, id, is_live).into_iter().skip(1) {
+                let access = self.access(&guard, actor, subtask_id).await;
+                if access.is_some_and(|access| access >= Access::Write) {
+                    ids.push(subtask_id);
+                }
+            }
+        }
+
+        let before = snapshot(&guard, &ids);
+        check_version(&before, id, expected_version)?;
+
+        if cascade {
+            for subtask_id in ids.iter().filter(|&&subtask_id| subtask_id != id) {
+                if let Some(subtask) = guard.get_mut(subtask_id).filter(|subtask| !subtask.done) {
+                    set_done(subtask, true);
+                    subtask.version += 1;
+                }
+            }
+        }
+
+        let Some(todo) = guard.get_mut(&id) else {
+            return Ok(None);
+        };
+
+        if let Some(title) = patch.title {
+            todo.title = title;
+        }
+
+        if let Some(description) = patch.description {
+            todo.description = description;
+        }
+
+        if let Some(done) = patch.done {
+            set_done(todo, done);
+        }
+
+        if let Some(due_at) = patch.due_at {
+            todo.due_at = due_at;
+        }
+
+        if let Some(priority) = patch.priority {
+            todo.priority = priority;
+        }
+
+        if let Some(parent_id) = patch.parent_id {
+            todo.parent_id = parent_id;
+        }
+
+        todo.version += 1;
+        let todo = todo.clone();
+
+        self.record_events(actor, &before, &snapshot(&guard, &ids))
+            .await;
+
+        Ok(Some(todo))
+    }
+
+    async fn delete(
+        &self,
+        actor: &Actor,
+        id: i64,
+        expected_version: Option<i64>,
+    ) -> Result<bool, TodoError> {
+        let mut guard = self.todos.lock().await;
+
+        if !permits(self.access(&guard, actor, id).await, Access::Owner, id)? {
+            return Ok(false);
+        }
+
+        let deleted_at = OffsetDateTime::now_utc();
+        let ids = subtree_ids(&guard, id, is_live);
+        let before = snapshot(&guard, &ids);
+        check_version(&before, id, expected_version)?;
+
+        for id in &ids {
+            if let Some(todo) = guard.get_mut(id) {
+                todo.deleted_at = Some(deleted_at);
+                todo.version += 1;
+            }
+        }
+
+        self.record_events(actor, &before, &snapshot(&guard, &ids))
+            .await;
+
+        Ok(!ids.is_empty())
+    }
+
+    async fn restore(&self, actor: &Actor, id: i64) -> Result<Option<Todo>, TodoError> {
+        let mut guard = self.todos.lock().await;
+
+        if !permits(self.access(&guard, actor, id).await, Access::Owner, id)? {
+            return Ok(None);
+        }
+
+        let Some(deleted_at) = guard.get(&id).and_then(|todo| todo.deleted_at) else {
+            return Ok(None);
+        };
+
+        if let Some(parent_id) = guard.get(&id).and_then(|todo| todo.parent_id) {
+            if live(&guard, parent_id).is_none() {
+                return Err(TodoError::ParentDeleted { id, parent_id });
+            }
+        }
+
+        // the subtasks deleted along with the todo share its `deleted_at`
+        let ids = subtree_ids(&guard, id, |todo| todo.deleted_at == Some(deleted_at));
+        let before = snapshot(&guard, &ids);
+
+        for id in &ids {
+            if let Some(todo) = guard.get_mut(id) {
+                todo.deleted_at = None;
+                todo.version += 1;
+            }
+        }
+
+        self.record_events(actor, &before, &snapshot(&guard, &ids))
+            .await;
+
+        Ok(guard.get(&id).cloned())
+    }
+
+    async fn trash(&self, actor: &Actor) -> Result<Vec<Todo>, TodoError> {
+        let guard = self.todos.lock().await;
+
+        let mut todos: Vec<Todo> = guard
+            .values()
+            .filter(|todo| todo.deleted_at.is_some() && todo.owner_id == actor.id)
+            .cloned()
+            .collect();
+        todos.sort_by_key(|todo| (std::cmp::Reverse(todo.deleted_at), todo.id));
+
+        Ok(todos)
+    }
+
+    async fn purge(&self, deleted_before: OffsetDateTime) -> Result<u64, TodoError> {
+        let mut guard = self.todos.lock().await;
+
+        let ids: Vec<i64> = guard
+            .values()
+            .filter(|todo| todo.deleted_at.is_some_and(|at| at < deleted_before))
+            .flat_map(|todo| subtree_ids(&guard, todo.id, |_| true))
+            .collect();
+
+        let readers: Vec<i64> = {
+            let shares = self.shares.lock().await;
+            ids.iter()
+                .filter_map(|id| guard.get(id))
+                .flat_map(|todo| readers(&shares, todo))
+                .collect()
+        };
+        let purged = ids.iter().filter(|id| guard.remove(id).is_some()).count();
+        self.touch_feeds(&readers).await;
+
+        self.shares
+            .lock()
+            .await
+            .retain(|(todo_id, _), _| guard.contains_key(todo_id));
+
+        Ok(purged as u64)
+    }
+
+    async fn history(&self, actor: &Actor, id: i64) -> Result<Option<Vec<TodoEvent>>, TodoError> {
+        let guard = self.todos.lock().await;
+
+        if self.access(&guard, actor, id).await.is_none() {
+            return Ok(None);
+        }
+
+        let events = self.events.lock().await;
+
+        Ok(Some(
+            events
+                .iter()
+                .filter(|event| event.todo_id == id)
+                .cloned()
+                .collect(),
+        ))
+    }
+
+    async fn changes_since(
+        &self,
+        actor: &Actor,
+        after: i64,
+        limit: i64,
+    ) -> Result<Vec<TodoChange>, TodoError> {
+        let shared = self.shared_with(actor).await;
+        let guard = self.todos.lock().await;
+        let events = self.events.lock().await;
+        let shares = self.shares.lock().await;
+
+        Ok(events
+            .iter()
+            .filter(|event| event.id > after)
+            .filter_map(|event| {
+                let todo = guard.get(&event.todo_id)?.clone();
+                if !can_see(&todo, actor, &shared) {
+                    return None;
+                }
+
+                let readers = readers(&shares, &todo);
+
+                Some(TodoChange {
+                    event: event.clone(),
+                    todo,
+                    readers,
+                })
+            })
+            .take(limit as usize)
+            .collect())
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<TodoChange> {
+        self.changes.subscribe()
+    }
+
+    async fn export(
+        &self,
+        actor: &Actor,
+    ) -> Result<BoxStream<'static, Result<Todo, TodoError>>, TodoError> {
+        let guard = self.todos.lock().await;
+
+        let mut todos: Vec<Todo> = guard
+            .values()
+            .filter(|todo| is_live(todo) && todo.owner_id == actor.id)
+            .cloned()
+            .collect();
+        todos.sort_by_key(|todo| (todo.created_at, todo.id));
+
+        Ok(futures::stream::iter(todos.into_iter().map(Ok)).boxed())
+    }
+
+    async fn batch(
+        &self,
+        actor: &Actor,
+        mode: BatchMode,
+        operations: Vec<BatchOperation>,
+    ) -> Result<Vec<BatchOutcome>, TodoError> {
+        let len = operations.len();
+        let start = self.savepoint().await;
+        let mut outcomes = Vec::with_capacity(len);
+
+        for operation in operations {
+            let savepoint = match mode {
+                BatchMode::Atomic => None,
+                BatchMode::BestEffort => Some(self.savepoint().await),
+            };
+
+            match self.apply(actor, operation).await {
+                Ok(outcome) => outcomes.push(outcome),
+                Err(error) => {
+                    outcomes.push(BatchOutcome::Failed(error));
+
+                    match savepoint {
+                        Some(savepoint) => self.roll_back_to(savepoint).await,
+                        None => {
+                            self.roll_back_to(start).await;
+
+                            return Ok(roll_back(outcomes, len));
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(outcomes)
+    }
+
+    async fn move_todo(
+        &self,
+        actor: &Actor,
+        id: i64,
+        list_id: Option<i64>,
+    ) -> Result<Option<Todo>, TodoError> {
+        if let Some(list_id) = list_id {
+            self.require_list(actor, list_id).await?;
+        }
+
+        let mut guard = self.todos.lock().await;
+
+        if !permits(self.access(&guard, actor, id).await, Access::Owner, id)? {
+            return Ok(None);
+        }
+
+        let Some(todo) = live_mut(&mut guard, id) else {
+            return Ok(None);
+        };
+
+        let before = todo.clone();
+        todo.list_id = list_id;
+        todo.version += 1;
+        let after = todo.clone();
+
+        self.record_events(actor, &[before], std::slice::from_ref(&after))
+            .await;
+
+        Ok(Some(after))
+    }
+
+    async fn add_tags(
+        &self,
+        actor: &Actor,
+        id: i64,
+        tags: Vec<String>,
+    ) -> Result<Option<Todo>, TodoError> {
+        let tags = normalize_tags(tags)?;
+
+        let mut guard = self.todos.lock().await;
+
+        if !permits(self.access(&guard, actor, id).await, Access::Write, id)? {
+            return Ok(None);
+        }
+
+        let Some(todo) = live_mut(&mut guard, id) else {
+            return Ok(None);
+        };
+
+        self.tags.lock().await.extend(tags.iter().cloned());
+
+        let before = todo.clone();
+        let merged: BTreeSet<String> = todo.tags.drain(..).chain(tags).collect();
+        todo.tags = merged.into_iter().collect();
+        todo.version += 1;
+        let after = todo.clone();
+
+        self.record_events(actor, &[before], std::slice::from_ref(&after))
+            .await;
+
+        Ok(Some(after))
+    }
+
+    async fn remove_tag(
+        &self,
+        actor: &Actor,
+        id: i64,
+        tag: String,
+    ) -> Result<Option<Todo>, TodoError> {
+        let mut guard = self.todos.lock().await;
+
+        if !permits(self.access(&guard, actor, id).await, Access::Write, id)? {
+            return Ok(None);
+        }
+
+        let Some(todo) = live_mut(&mut guard, id) else {
+            return Ok(None);
+        };
+
+        let before = todo.clone();
+        todo.tags.retain(|existing| existing != tag.trim());
+        todo.version += 1;
+        let after = todo.clone();
+
+        self.record_events(actor, &[before], std::slice::from_ref(&after))
+            .await;
+
+        Ok(Some(after))
+    }
+
+    async fn get_tags(&self, actor: &Actor) -> Result<Vec<TagCount>, TodoError> {
+        let shared = self.shared_with(actor).await;
+        let todos = self.todos.lock().await;
+        let tags = self.tags.lock().await;
+
+        let visible: Vec<&Todo> = todos
+            .values()
+            .filter(|todo| can_see(todo, actor, &shared))
+            .collect();
+
+        Ok(tags
+            .iter()
+            .filter(|name| visible.iter().any(|todo| todo.tags.contains(name)))
+            .map(|name| TagCount {
+                name: name.clone(),
+                count: visible
+                    .iter()
+                    .filter(|todo| is_live(todo) && todo.tags.contains(name))
+                    .count() as i64,
+            })
+            .collect())
+    }
+
+    async fn get_lists(&self, actor: &Actor) -> Result<Vec<TodoList>, TodoError> {
+        let guard = self.lists.lock().await;
+
+        Ok(guard
+            .values()
+            .filter(|list| list.owner_id == actor.id)
+            .cloned()
+            .collect())
+    }
+
+    async fn create_list(&self, actor: &Actor, name: String) -> Result<TodoList, TodoError> {
+        let mut guard = self.lists.lock().await;
+
+        let id = {
+            let mut counter_guard = self.list_counter.lock().await;
+            *counter_guard += 1;
+            *counter_guard
+        };
+
+        let list = TodoList {
+            id,
+            name,
+            created_at: OffsetDateTime::now_utc(),
+            owner_id: actor.id,
+        };
+        guard.insert(id, list.clone());
+
+        Ok(list)
+    }
+
+    async fn get_list(&self, actor: &Actor, id: i64) -> Result<Option<TodoList>, TodoError> {
+        let guard = self.lists.lock().await;
+
+        Ok(guard
+            .get(&id)
+            .filter(|list| list.owner_id == actor.id)
+            .cloned())
+    }
+
+    async fn rename_list(
+        &self,
+        actor: &Actor,
+        id: i64,
+        name: String,
+    ) -> Result<Option<TodoList>, TodoError> {
+        let mut guard = self.lists.lock().await;
+
+        Ok(guard
+            .get_mut(&id)
+            .filter(|list| list.owner_id == actor.id)
+            .map(|list| {
+                list.name = name;
+                list.clone()
+            }))
+    }
+
+    async fn delete_list(&self, actor: &Actor, id: i64) -> Result<bool, TodoError> {
+        let mut guard = self.lists.lock().await;
+
+        if guard.get(&id).is_none_or(|list| list.owner_id != actor.id) {
+            return Ok(false);
+        }
+        guard.remove(&id);
+
+        let mut todos = self.todos.lock().await;
+
+        // the todos in the list go to the trash, along with their subtasks
+        // even when they are in another list
+        let in_list = |todo: &Todo| todo.list_id == Some(id);
+        let ids: Vec<i64> = todos
+            .values()
+            .filter(|todo| in_list(todo))
+            .flat_map(|todo| subtree_ids(&todos, todo.id, |todo| in_list(todo) || is_live(todo)))
+            .collect::<BTreeSet<i64>>()
+            .into_iter()
+            .collect();
+        let before = snapshot(&todos, &ids);
+
+        let deleted_at = OffsetDateTime::now_utc();
+        for todo_id in &ids {
+            if let Some(todo) = todos.get_mut(todo_id) {
+                todo.deleted_at = todo.deleted_at.or(Some(deleted_at));
+                todo.list_id = todo.list_id.filter(|&list_id| list_id != id);
+                todo.version += 1;
+            }
+        }
+
+        self.record_events(actor, &before, &snapshot(&todos, &ids))
+            .await;
+
+        Ok(true)
+    }
+
+    async fn due_between(
+        &self,
+        actor: &Actor,
+        from: Option<OffsetDateTime>,
+        to: OffsetDateTime,
+    ) -> Result<Vec<Todo>, TodoError> {
+        let shared = self.shared_with(actor).await;
+        let guard = self.todos.lock().await;
+
+        let mut todos: Vec<Todo> = guard
+            .values()
+            .filter(|todo| is_live(todo) && !todo.done && can_see(todo, actor, &shared))
+            .filter(|todo| {
+                todo.due_at
+                    .is_some_and(|due_at| due_at < to && from.is_none_or(|from| due_at >= from))
+            })
+            .cloned()
+            .collect();
+        todos.sort_by_key(|todo| (todo.due_at, todo.id));
+
+        Ok(todos)
+    }
+
+    async fn scheduled(&self, actor: &Actor) -> Result<Vec<Todo>, TodoError> {
+        let shared = self.shared_with(actor).await;
+        let guard = self.todos.lock().await;
+
+        let mut todos: Vec<Todo> = guard
+            .values()
+            .filter(|todo| is_live(todo) && todo.due_at.is_some())
+            .filter(|todo| can_see(todo, actor, &shared))
+            .cloned()
+            .collect();
+        todos.sort_by_key(|todo| (todo.due_at, todo.id));
+
+        Ok(todos)
+    }
+
+    async fn last_changed(&self, actor: &Actor) -> Result<Option<OffsetDateTime>, TodoError> {
+        let feeds = self.calendar_feeds.lock().await;
+
+        Ok(feeds.get(&actor.id).map(|(_, changed_at)| *changed_at))
+    }
+
+    async fn calendar_token(&self, actor: &Actor) -> Result<String, TodoError> {
+        let mut feeds = self.calendar_feeds.lock().await;
+
+        let (token, _) = feeds
+            .entry(actor.id)
+            .or_insert_with(|| (new_token(), OffsetDateTime::now_utc()));
+
+        Ok(token.clone())
+    }
+
+    async fn rotate_calendar_token(&self, actor: &Actor) -> Result<String, TodoError> {
+        let token = new_token();
+        self.calendar_feeds
+            .lock()
+            .await
+            .entry(actor.id)
+            .and_modify(|(feed_token, _)| feed_token.clone_from(&token))
+            .or_insert_with(|| (token.clone(), OffsetDateTime::now_utc()));
+
+        Ok(token)
+    }
+
+    async fn calendar_owner(&self, token: &str) -> Result<Option<Actor>, TodoError> {
+        let feeds = self.calendar_feeds.lock().await;
+        let users = self.users.lock().await;
+
+        Ok(feeds
+            .iter()
+            .find(|(_, (feed_token, _))| feed_token.as_str() == token)
+            .and_then(|(id, _)| {
+                Some(Actor {
+                    id: *id,
+                    name: users.get(id)?.clone(),
+                })
+            }))
+    }
+
+    async fn shares(&self, actor: &Actor, id: i64) -> Result<Vec<TodoShare>, TodoError> {
+        require(
+            self.access(&*self.todos.lock().await, actor, id).await,
+            Access::Owner,
+            id,
+        )?;
+
+        let shares = self.shares.lock().await;
+        let users = self.users.lock().await;
+
+        let mut shares: Vec<TodoShare> = shares
+            .iter()
+            .filter(|((todo_id, _), _)| *todo_id == id)
+            .filter_map(|((_, user_id), permission)| {
+                Some(TodoShare {
+                    user: users.get(user_id)?.clone(),
+                    permission: *permission,
+                })
+            })
+            .collect();
+        shares.sort_by(|left, right| left.user.cmp(&right.user));
+
+        Ok(shares)
+    }
+
+    async fn share(
+        &self,
+        actor: &Actor,
+        id: i64,
+        user: &str,
+        permission: Permission,
+    ) -> Result<TodoShare, TodoError> {
+        require(
+            self.access(&*self.todos.lock().await, actor, id).await,
+            Access::Owner,
+            id,
+        )?;
+
+        let user_id = self.find_user(user).await?;
+        if user_id == actor.id {
+            return Err(TodoError::BadRequest {
+                message: "Todos cannot be shared with their owner".to_string(),
+            });
+        }
+
+        self.shares.lock().await.insert((id, user_id), permission);
+        self.touch_feeds(&[user_id]).await;
+
+        Ok(TodoShare {
+            user: user.to_string(),
+            permission,
+        })
+    }
+
+    async fn unshare(&self, actor: &Actor, id: i64, user: &str) -> Result<(), TodoError> {
+        require(
+            self.access(&*self.todos.lock().await, actor, id).await,
+            Access::Owner,
+            id,
+        )?;
+
+        let user_id = self.find_user(user).await?;
+        if self.shares.lock().await.remove(&(id, user_id)).is_some() {
+            self.touch_feeds(&[user_id]).await;
+        }
+
+        Ok(())
+    }
+}
+
+fn is_live(todo: &Todo) -> bool {
+    todo.deleted_at.is_none()
+}
+
+// The owner of the todo followed by everyone it is shared with.
+fn readers(shares: &BTreeMap<(i64, i64), Permission>, todo: &Todo) -> Vec<i64> {
+    std::iter::once(todo.owner_id)
+        .chain(
+            shares
+                .keys()
+                .filter(|(todo_id, _)| *todo_id == todo.id)
+                .map(|(_, user_id)| *user_id),
+        )
+        .collect()
+}
+
+// Whether the actor owns the todo, or it is one of those `shared` with them.
+fn can_see(todo: &Todo, actor: &Actor, shared: &BTreeSet<i64>) -> bool {
+    todo.owner_id == actor.id || shared.contains(&todo.id)
+}
+
+// The todo with the given id, unless it is in the trash.
+fn live(todos: &BTreeMap<i64, Todo>, id: i64) -> Option<&Todo> {
+    todos.get(&id).filter(|todo| is_live(todo))
+}
+
+fn live_mut(todos: &mut BTreeMap<i64, Todo>, id: i64) -> Option<&mut Todo> {
+    todos.get_mut(&id).filter(|todo| is_live(todo))
+}
+
+// The id of the todo followed by the ids of all of its subtasks, following
+// only the todos that pass `include`. Empty if the todo itself does not.
+fn subtree_ids(todos: &BTreeMap<i64, Todo>, id: i64, include: impl Fn(&Todo) -> bool) -> Vec<i64> {
+    if !todos.get(&id).is_some_and(&include) {
+        return Vec::new();
+    }
+
+    let mut ids = vec![id];
+    let mut next = 0;
+    while let Some(&parent_id) = ids.get(next) {
+        ids.extend(
+            todos
+                .values()
+                .filter(|todo| todo.parent_id == Some(parent_id) && include(todo))
+                .map(|todo| todo.id),
+        );
+        next += 1;
+    }
+
+    ids
+}
+
+// The in-memory counterpart of `check_parent`.
+fn check_parent_in(
+    todos: &BTreeMap<i64, Todo>,
+    id: Option<i64>,
+    parent_id: i64,
+    max_depth: usize,
+) -> Result<(), TodoError> {
+    let mut ancestors = Vec::new();
+    let mut next = live(todos, parent_id);
+    while let Some(ancestor) = next {
+        if Some(ancestor.id) == id {
+            return Err(TodoError::Cycle {
+                id: ancestor.id,
+                parent_id,
+            });
+        }
+
+        ancestors.push(ancestor.id);
+        next = ancestor.parent_id.and_then(|id| todos.get(&id));
+    }
+
+    if ancestors.is_empty() {
+        return Err(TodoError::ParentNotFound { id: parent_id });
+    }
+
+    let height = match id {
+        Some(id) => subtree_height(todos, id),
+        None => 0,
+    };
+
+    if ancestors.len() + height > max_depth {
+        return Err(TodoError::TooDeep { max_depth });
+    }
+
+    Ok(())
+}
+
+fn subtree_height(todos: &BTreeMap<i64, Todo>, id: i64) -> usize {
+    todos
+        .values()
+        .filter(|todo| todo.parent_id == Some(id))
+        .map(|todo| subtree_height(todos, todo.id) + 1)
+        .max()
+        .unwrap_or(0)
+}
+
+// Mirrors the `todos_set_completed_at` trigger.
+fn set_done(todo: &mut Todo, done: bool) {
+    if done && !todo.done {
+        todo.completed_at = Some(OffsetDateTime::now_utc());
+    } else if !done {
+        todo.completed_at = None;
+    }
+
+    todo.done = done;
+}
@@ -29,6 +29,8 @@ use axum::{
 use http_body_util::BodyExt;
 use hyper::Request;
 
+use crate::error::AppError;
+
 ///
 /// EXERCISE 1
 ///
@@ -680,9 +682,14 @@ async fn result_handler_test() {
         .unwrap();
 
     assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    // errors say what went wrong as problem details, not just with a status
+    assert_eq!(
+        response.headers()["content-type"],
+        crate::error::PROBLEM_JSON
+    );
 }
-async fn result_handler() -> Result<String, hyper::StatusCode> {
-    Err(hyper::StatusCode::INTERNAL_SERVER_ERROR) // okay, seems pointless
+async fn result_handler() -> Result<String, AppError> {
+    Err(AppError::Internal("the handler always fails".to_string()))
 }
 
 ///
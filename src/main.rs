@@ -1,23 +1,47 @@
 mod architecture;
 mod basics;
 mod client;
+mod config;
 mod context;
+mod error;
+mod finalthing;
 mod handlers;
 mod middleware;
 mod persistence;
 mod playground;
+mod validation;
 mod welcome;
 
+use config::{Args, Config, ConfigError};
+
 #[tokio::main]
 async fn main() {
+    let args = Args::parse(std::env::args().skip(1)).unwrap_or_else(|error| exit(error));
+    let config = Config::load(args.config_file().as_deref()).unwrap_or_else(|error| exit(error));
+
+    if args.print_config {
+        print!("{}", config.redacted());
+        return;
+    }
+    error::set_log_format(config.log.format);
+
     // playground::example_postgres().await.unwrap();
-    // basics::hello_world().await;
-    // context::run_users_server().await;
+    // basics::hello_world(&config).await;
+    // context::run_users_server(&config).await;
 
-    // client::cat_fact_server().await;
-    // client::posts_server().await;
-    // client::graduation_project().await; // this is the wine server I made 🍷
-    persistence::run_todo_app().await;
+    // client::cat_fact_server(&config).await;
+    // client::posts_server(&config).await;
+    // client::graduation_project(&config).await; // this is the wine server I made 🍷
+    persistence::run_todo_app(&config)
+        .await
+        .unwrap_or_else(|error| exit(error));
 
     println!("Hello, world!");
 }
+
+// A bad command line or config is for whoever started the app to fix, so it
+// gets a message rather than a panic.
+fn exit(error: ConfigError) -> ! {
+    eprintln!("{}", error);
+    std::process::exit(2);
+}
@@ -3,6 +3,8 @@
 use axum::{response::Html, routing::*, Router};
 use sqlx::postgres::PgPoolOptions;
 
+use crate::config::Config;
+
 ///
 /// This example shows you how to use the `testcontainers` crate to run a
 /// Postgres database in a Docker container, and how to make basic queries
@@ -33,12 +35,12 @@ pub async fn example_postgres() -> Result<(), sqlx::Error> {
     Ok(())
 }
 
-pub async fn example_axum() {
+pub async fn example_axum(config: &Config) {
     // build our application with a route
     let app = Router::new().route("/", get(|| async move { Html("Hello, World!") }));
 
     // run it
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:3000")
+    let listener = tokio::net::TcpListener::bind(config.server.bind)
         .await
         .unwrap();
 
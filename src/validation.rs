@@ -0,0 +1,309 @@
+//!
+//! VALIDATION
+//! ----------
+//!
+//! `Json` only checks that a body has the right shape: an empty title, a
+//! description of ten megabytes or an email without an `@` all make it
+//! through. `ValidatedJson` also checks the rules its type declares with
+//! `#[validate(...)]`, and rejects the request with a 422 problem that
+//! lists every field that broke one of them:
+//!
+//! {
+//!   "type": "/problems/validation-failed",
+//!   "status": 422,
+//!   "errors": [
+//!     { "field": "title", "rule": "length", "message": "must be 1 to 200 characters long" },
+//!     { "field": "email", "rule": "email", "message": "must be an email address" }
+//!   ],
+//!   ...
+//! }
+//!
+//! Bodies that are not JSON at all are rejected with the line and column
+//! where parsing failed, so that the client can find the mistake.
+//!
+
+use axum::{
+    body::Bytes,
+    extract::{FromRequest, Request},
+    http::{header, HeaderMap, StatusCode},
+};
+use serde::de::DeserializeOwned;
+use validator::{Validate, ValidationError, ValidationErrors, ValidationErrorsKind};
+
+use crate::error::{AppError, Violation};
+
+///
+/// Extracts a JSON body of type `T`, like `Json<T>`, and validates it.
+///
+/// async fn create_user(ValidatedJson(user): ValidatedJson<NewUser>) { ... }
+///
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct ValidatedJson<T>(pub(crate) T);
+
+#[axum::async_trait]
+impl<T, S> FromRequest<S> for ValidatedJson<T>
+where
+    T: DeserializeOwned + Validate,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        if !json_content_type(req.headers()) {
+            return Err(AppError::problem(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "missing-json-content-type",
+                "Expected request with `Content-Type: application/json`",
+            ));
+        }
+
+        let body = Bytes::from_request(req, state).await.map_err(|rejection| {
+            AppError::problem(rejection.status(), "unreadable-body", rejection.body_text())
+        })?;
+
+        let value: T = parse(&body)?;
+        value
+            .validate()
+            .map_err(|errors| AppError::Invalid(violations(&errors)))?;
+
+        Ok(ValidatedJson(value))
+    }
+}
+
+fn json_content_type(headers: &HeaderMap) -> bool {
+    let Some(content_type) = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+    else {
+        return false;
+    };
+
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    essence == "application/json"
+        || (essence.starts_with("application/") && essence.ends_with("+json"))
+}
+
+// Keeps track of where in the body deserializing failed, so that a value of
+// the wrong type can be reported against its field.
+fn parse<T: DeserializeOwned>(body: &[u8]) -> Result<T, AppError> {
+    let mut deserializer = serde_json::Deserializer::from_slice(body);
+
+    let value = serde_path_to_error::deserialize(&mut deserializer).map_err(|error| {
+        AppError::InvalidJson {
+            path: error.path().to_string(),
+            error: error.into_inner(),
+        }
+    })?;
+    deserializer.end().map_err(|error| AppError::InvalidJson {
+        path: ".".to_string(),
+        error,
+    })?;
+
+    Ok(value)
+}
+
+///
+/// Every rule the value broke, with the path of its field: `title`,
+/// `contact.email` or `tags[2]`. Sorted by field, as the errors of a
+/// struct are kept in a map.
+///
+pub(crate) fn violations(errors: &ValidationErrors) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    collect_violations("", errors, &mut violations);
+    violations.sort_by(|a, b| (&a.field, &a.rule).cmp(&(&b.field, &b.rule)));
+
+    violations
+}
+
+fn collect_violations(prefix: &str, errors: &ValidationErrors, violations: &mut Vec<Violation>) {
+    for (field, kind) in errors.errors() {
+        let path = if prefix.is_empty() {
+            field.to_string()
+        } else {
+            format!("{}.{}", prefix, field)
+        };
+
+        match kind {
+            ValidationErrorsKind::Field(errors) => {
+                violations.extend(errors.iter().map(|error| Violation {
+                    field: path.clone(),
+                    rule: error.code.to_string(),
+                    message: describe(error),
+                }))
+            }
+            ValidationErrorsKind::Struct(errors) => collect_violations(&path, errors, violations),
+            ValidationErrorsKind::List(items) => {
+                for (index, errors) in items {
+                    collect_violations(&format!("{}[{}]", path, index), errors, violations);
+                }
+            }
+        }
+    }
+}
+
+// What the rule asks of the field, unless it came with a message of its own.
+fn describe(error: &ValidationError) -> String {
+    if let Some(message) = &error.message {
+        return message.to_string();
+    }
+
+    let param = |name: &str| error.params.get(name).map(|value| value.to_string());
+
+    match error.code.as_ref() {
+        "length" => {
+            let unit = if error
+                .params
+                .get("value")
+                .is_some_and(|value| value.is_string())
+            {
+                "characters"
+            } else {
+                "items"
+            };
+
+            match (param("min"), param("max"), param("equal")) {
+                (_, _, Some(equal)) => format!("must be exactly {} {} long", equal, unit),
+                (Some(min), Some(max), _) => format!("must be {} to {} {} long", min, max, unit),
+                (Some(min), None, _) => format!("must be at least {} {} long", min, unit),
+                (None, Some(max), _) => format!("must be at most {} {} long", max, unit),
+                (None, None, None) => "has the wrong length".to_string(),
+            }
+        }
+        "range" => match (param("min"), param("max")) {
+            (Some(min), Some(max)) => format!("must be between {} and {}", min, max),
+            (Some(min), None) => format!("must be at least {}", min),
+            (None, Some(max)) => format!("must be at most {}", max),
+            (None, None) => "is out of range".to_string(),
+        },
+        "email" => "must be an email address".to_string(),
+        "url" => "must be a URL".to_string(),
+        "regex" => "is not in the expected format".to_string(),
+        code => format!("breaks the `{}` rule", code),
+    }
+}
+
+/// Fails for text that is empty or only whitespace.
+pub(crate) fn not_blank(value: &str) -> Result<(), ValidationError> {
+    if value.trim().is_empty() {
+        return Err(ValidationError::new("not_blank").with_message("must not be blank".into()));
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn validated_json_reports_every_broken_rule() {
+    use crate::error::Problem;
+    use axum::{body::Body, http::Method, routing::post, Router};
+    use hyper::Request;
+    use regex::Regex;
+    use std::sync::LazyLock;
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+
+    static POSTCODE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^\d{4}[A-Z]{2}$").unwrap());
+
+    fn no_admins(name: &str) -> Result<(), ValidationError> {
+        if name.eq_ignore_ascii_case("admin") {
+            return Err(ValidationError::new("reserved"));
+        }
+
+        Ok(())
+    }
+
+    #[derive(serde::Deserialize, Validate)]
+    struct Address {
+        #[validate(regex(path = *POSTCODE))]
+        postcode: String,
+    }
+
+    #[derive(serde::Deserialize, Validate)]
+    struct Signup {
+        #[validate(length(min = 1, max = 20), custom(function = "no_admins"))]
+        name: String,
+        #[validate(email)]
+        email: String,
+        #[validate(range(min = 18, max = 150))]
+        age: u32,
+        #[validate(nested)]
+        addresses: Vec<Address>,
+    }
+
+    let app = Router::new().route(
+        "/",
+        post(|ValidatedJson(_): ValidatedJson<Signup>| async { StatusCode::NO_CONTENT }),
+    );
+
+    let send = |body: &'static str| {
+        app.clone().oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri("/")
+                .header("Content-Type", "application/json")
+                .body(Body::from(body))
+                .unwrap(),
+        )
+    };
+
+    let response = send(
+        r#"{"name": "Ada", "email": "ada@example.com", "age": 36, "addresses": [{"postcode": "1234AB"}]}"#,
+    )
+    .await
+    .unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    let response = send(
+        r#"{"name": "admin", "email": "admin", "age": 7, "addresses": [{"postcode": "1234AB"}, {"postcode": "AB"}]}"#,
+    )
+    .await
+    .unwrap();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let problem: Problem = serde_json::from_slice(&body).unwrap();
+    assert_eq!(problem.problem_type, "/problems/validation-failed");
+    let broken: Vec<_> = problem
+        .errors
+        .iter()
+        .map(|violation| (violation.field.as_str(), violation.rule.as_str()))
+        .collect();
+    assert_eq!(
+        broken,
+        [
+            ("addresses[1].postcode", "regex"),
+            ("age", "range"),
+            ("email", "email"),
+            ("name", "reserved"),
+        ]
+    );
+    assert_eq!(problem.errors[1].message, "must be between 18 and 150");
+
+    // a value of the wrong type is reported against its field
+    let response = send(r#"{"name": "Ada", "email": "ada@example.com", "age": "old"}"#)
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let problem: Problem = serde_json::from_slice(&body).unwrap();
+    assert_eq!(problem.problem_type, "/problems/invalid-json-data");
+    assert_eq!(problem.errors[0].field, "age");
+    assert_eq!(problem.errors[0].rule, "type");
+
+    // and JSON that does not parse says where it stopped making sense
+    let response = send("{\n  \"name\": \"Ada\",\n  \"email\" \"ada@example.com\"\n}")
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let problem: Problem = serde_json::from_slice(&body).unwrap();
+    assert_eq!(problem.problem_type, "/problems/invalid-json");
+    assert_eq!((problem.line, problem.column), (Some(3), Some(11)));
+    assert!(problem.detail.contains("line 3 column 11"));
+}